@@ -1,6 +1,8 @@
 import { DynamoDBClient, QueryCommand } from "@aws-sdk/client-dynamodb";
 import FormData from "form-data";
+import fs from "fs";
 import fetch from "node-fetch";
+import path from "path";
 
 // .env に書け
 const region = "ap-northeast-1";
@@ -18,6 +20,37 @@ const client = new DynamoDBClient({ region });
 
 const MAX_PROBLEM_ID = 40;
 
+// 公式のスコアを solver の verify に記録する (solver.sh verify で手元のスコアと比べる)
+const VERIFY_DIR = path.join(__dirname, "../solver/verify");
+
+type VerifyCase = {
+  problem_id: string;
+  program: string;
+  score: number;
+  source: string;
+};
+
+function recordOfficialScore(
+  problemId: number,
+  isl: string,
+  cost: number,
+  submissionId: number
+) {
+  const expectedPath = path.join(VERIFY_DIR, "expected.json");
+  const cases: VerifyCase[] = JSON.parse(
+    fs.readFileSync(expectedPath, "utf-8")
+  ).filter((c: VerifyCase) => c.problem_id !== `${problemId}`);
+  fs.writeFileSync(path.join(VERIFY_DIR, `${problemId}.isl`), isl);
+  cases.push({
+    problem_id: `${problemId}`,
+    program: `${problemId}.isl`,
+    score: cost,
+    source: `robovinci.xyz submission ${submissionId}`,
+  });
+  cases.sort((a, b) => parseInt(a.problem_id, 10) - parseInt(b.problem_id, 10));
+  fs.writeFileSync(expectedPath, JSON.stringify(cases, null, 4) + "\n");
+}
+
 (async () => {
   for (let problemId = 1; problemId <= MAX_PROBLEM_ID; problemId++) {
     const { Items: items } = await client.send(
@@ -75,6 +108,9 @@ const MAX_PROBLEM_ID = 40;
       if (cost !== score) {
         console.log(`WRONG SCORE: expect ${score}, but ${cost} given`);
       }
+      if (typeof cost === "number") {
+        recordOfficialScore(problemId, isl, cost, submissionId);
+      }
       break;
     }
   }
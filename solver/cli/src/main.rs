@@ -1,6 +1,6 @@
 extern crate core;
 
 fn main() -> anyhow::Result<()> {
-    core::run()?;
+    core::cli()?;
     Ok(())
 }
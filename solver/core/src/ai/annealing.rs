@@ -2,17 +2,21 @@ use std::time::{Duration, Instant};
 
 use crate::{
     ai::ChainedAI,
+    context::SolveContext,
     image::Image,
+    incremental::{IncrementalSimulator, CHECKPOINT_INTERVAL},
     isl::{Move, Orientation, Program},
-    simulator::{calc_score, simulate_all, ProgramExecError, State},
+    simulator::{calc_state_similarity, ProgramExecError, State},
 };
 use glam::IVec2;
 use log::debug;
 use log::info;
 use rand::prelude::*;
 
+// 時間で打ち切るので、同じ seed でも同じ結果になるとは限らない
 pub struct AnnealingAI {
     pub time_limit: Duration,
+    pub seed: u64,
 }
 
 impl ChainedAI for AnnealingAI {
@@ -21,13 +25,24 @@ impl ChainedAI for AnnealingAI {
         image: &Image,
         initial_state: &State,
         initial_program: &Program,
+        ctx: &SolveContext,
     ) -> Program {
         let mut solution = initial_program.clone();
-        let mut rng = SmallRng::from_entropy();
-        let mut current_score = self
-            .calc_ann_score(&solution, image, initial_state)
-            .unwrap();
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        // 変更した手以降だけを再計算する
+        let mut sim = IncrementalSimulator::new(
+            initial_state,
+            image.width(),
+            image.height(),
+            CHECKPOINT_INTERVAL,
+        );
+        let mut current_score = self.calc_ann_score(&mut sim, &solution, image).unwrap();
         let start_at = Instant::now();
+        // パイプライン全体の締め切りの方が早ければそれに合わせて冷やす
+        let time_limit = match ctx.remaining() {
+            Some(remaining) => self.time_limit.min(remaining),
+            None => self.time_limit,
+        };
 
         let mut best_solution = solution.clone();
         let mut best_score = current_score;
@@ -41,13 +56,13 @@ impl ChainedAI for AnnealingAI {
             iter += 1;
             if iter % 100 == 0 {
                 let elapsed = Instant::now() - start_at;
-                if elapsed >= self.time_limit {
+                if elapsed >= time_limit || ctx.is_cancelled() {
                     info!("iter = {}", iter);
                     return best_solution;
                 }
 
                 // tweak temperature
-                let progress = elapsed.as_secs_f64() / self.time_limit.as_secs_f64();
+                let progress = elapsed.as_secs_f64() / time_limit.as_secs_f64();
                 temperature = initial_temperature * (1.0 - progress) * (-progress).exp2();
             }
 
@@ -66,9 +81,16 @@ impl ChainedAI for AnnealingAI {
             }
             let i_chosen = candidates[rng.gen::<usize>() % candidates.len()];
             let old = solution.0[i_chosen].clone();
-            let state = simulate_all(&solution, &initial_state, image.width(), image.height())
-                .unwrap()
-                .0;
+            let block = match old {
+                Move::LCut { ref block_id, .. } | Move::PCut { ref block_id, .. } => sim
+                    .simulate(&solution)
+                    .unwrap()
+                    .0
+                    .block(block_id)
+                    .unwrap()
+                    .clone(),
+                _ => unreachable!(),
+            };
             let delta = 5; // TODO
             let modified = match old {
                 Move::LCut {
@@ -76,7 +98,6 @@ impl ChainedAI for AnnealingAI {
                     orientation,
                     line_number,
                 } => {
-                    let block = state.blocks.get(block_id).unwrap();
                     let offset = line_number
                         - match orientation {
                             Orientation::Horizontal => block.p.y,
@@ -114,7 +135,6 @@ impl ChainedAI for AnnealingAI {
                     ref block_id,
                     point,
                 } => {
-                    let block = state.blocks.get(block_id).unwrap();
                     let dx = [-1, -1, -1, 0, 0, 1, 1, 1];
                     let dy = [-1, 0, 1, -1, 1, -1, 0, 1];
                     let mut n_candidates = 0;
@@ -147,7 +167,7 @@ impl ChainedAI for AnnealingAI {
             };
             solution.0[i_chosen] = modified;
 
-            let new_score = match self.calc_ann_score(&mut solution, image, initial_state) {
+            let new_score = match self.calc_ann_score(&mut sim, &solution, image) {
                 Ok(x) => x,
                 Err(_) => {
                     debug!("failed to move.. rollback.");
@@ -179,6 +199,7 @@ impl ChainedAI for AnnealingAI {
             if current_score < best_score {
                 best_score = current_score;
                 best_solution = solution.clone();
+                ctx.report(&best_solution, best_score as i64);
             }
         }
     }
@@ -186,10 +207,11 @@ impl ChainedAI for AnnealingAI {
 impl AnnealingAI {
     fn calc_ann_score(
         &self,
+        sim: &mut IncrementalSimulator,
         program: &Program,
         image: &Image,
-        state: &State,
     ) -> Result<f64, ProgramExecError> {
-        Ok(calc_score(program, image, state)? as f64)
+        let (state, move_cost) = sim.simulate(program)?;
+        Ok((move_cost + calc_state_similarity(state, image)) as f64)
     }
 }
@@ -1,4 +1,5 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
@@ -6,10 +7,17 @@ use crate::simulator;
 pub struct ChangeColorAI {}
 
 impl HeadAI for ChangeColorAI {
-    fn solve(&mut self, image: &image::Image, initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         let mut programs = vec![];
-        for (block_id, block) in initial_state.blocks.clone().iter() {
-            let color_average = image.average(block.p, block.size);
+        let stats = image::RectStats::new(image, &[]);
+        for (handle, block) in initial_state.active_blocks() {
+            let block_id = initial_state.block_id(handle);
+            let color_average = stats.average(block.p, block.size);
             let color_majority = image.majority(block.p, block.size);
             let next_move_average = isl::Move::Color {
                 block_id: block_id.clone(),
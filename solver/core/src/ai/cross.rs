@@ -1,8 +1,9 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 pub struct CrossAI {
     pub size: usize,
@@ -24,7 +25,7 @@ impl CrossAI {
             let mut sum = isl::Color::ZERO;
             for y in min.y..max.y {
                 for x in min.x..max.x {
-                    sum += image.0[y as usize][x as usize];
+                    sum += image.get(x as usize, y as usize);
                 }
             }
             let area = (max - min).y * (max - min).x;
@@ -92,12 +93,17 @@ impl CrossAI {
 }
 
 impl HeadAI for CrossAI {
-    fn solve(&mut self, image: &image::Image, _initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        _initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         // 再帰的 に pcut してく
         // 各マスの色に何を塗るかを集計して
         // 分割しなくていいならやめる (-> 再帰でなんかそれっぽく書く)
-        let height = image.0.len() as i32;
-        let width = image.0[0].len() as i32;
+        let height = image.height() as i32;
+        let width = image.width() as i32;
 
         let result = self.draw(
             isl::BlockId::new(&vec![0]),
@@ -108,7 +114,8 @@ impl HeadAI for CrossAI {
         );
 
         // 一番多いのは最初にぬっちゃう
-        let mut hash = HashMap::new();
+        // 同数のときも実行ごとに同じ色になるように、順序の決まった BTreeMap を使う
+        let mut hash = BTreeMap::new();
         for m in &result {
             if let isl::Move::Color { block_id: _, color } = m {
                 let s = isl::format_color(color);
@@ -0,0 +1,28 @@
+use crate::ai::ChainedAI;
+use crate::context::SolveContext;
+use crate::image;
+use crate::isl;
+use crate::optimizer;
+use crate::simulator;
+
+// 出力画像を変えずに無駄な手を消す
+pub struct DeadMoveAI {}
+
+impl ChainedAI for DeadMoveAI {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        initial_state: &simulator::State,
+        program: &isl::Program,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
+        let optimized =
+            optimizer::eliminate_dead_moves(program, initial_state, image.width(), image.height());
+        log::info!(
+            "DeadMove: {} moves -> {} moves",
+            program.len(),
+            optimized.len()
+        );
+        optimized
+    }
+}
@@ -1,13 +1,14 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl::*;
 use crate::simulator;
-use crate::simulator::BlockState;
-use crate::simulator::SimpleBlock;
+use crate::simulator::Block;
 use crate::simulator::State;
 use arrayvec::ArrayVec;
-use rand::rngs::ThreadRng;
+use rand::rngs::SmallRng;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
 #[allow(unused_imports)]
 use smallvec::smallvec;
 
@@ -34,7 +35,7 @@ impl Child {
 
 pub struct DpAI {
     divide_num: usize,
-    rng: ThreadRng,
+    rng: SmallRng,
     sample_color_num: usize,
     k_means_iter_num: usize,
     sampled_color: Vec<Color>,
@@ -42,28 +43,35 @@ pub struct DpAI {
     // memo_restore[color_id][x][y][w][h] -> Some(今のブロックに対するProgram, 復元用の次の最適解))
     memo: Vec<Vec<Vec<Vec<Vec<i32>>>>>,
     memo_restore: Vec<Vec<Vec<Vec<Vec<Option<(ArrayVec<Move, 2>, ArrayVec<Child, 4>)>>>>>>,
-    similality_memo: Vec<Vec<Vec<Option<i32>>>>,
+    // sampled_color で塗ったときの similarity と、初期状態のままのときの距離の累積和
+    rect_stats: image::RectStats,
+    initial_distance: image::PrefixSum<f64>,
     x_offsets: Vec<i32>,
     y_offsets: Vec<i32>,
     target_image: image::Image,
     initial_state: State,
     initial_block_id: Option<BlockId>,
-    initial_block: SimpleBlock,
+    initial_block: Block,
     initial_image: image::Image,
 }
 
 impl HeadAI for DpAI {
-    fn solve(&mut self, image: &image::Image, initial_state: &simulator::State) -> Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        initial_state: &simulator::State,
+        ctx: &SolveContext,
+    ) -> Program {
         let d = self.divide_num;
         self.target_image = image.clone();
         let mut ret = Program(vec![]);
         self.initial_state = initial_state.clone();
         if self.initial_block_id.is_none() {
             // first run
-            self.initial_block_id = Some(initial_state.blocks.keys().next().unwrap().clone());
-            if initial_state.blocks.len() != 1 {
+            self.initial_block_id = Some(initial_state.block_id(initial_state.active[0]));
+            if initial_state.active.len() != 1 {
                 let mut merge_ai = MergeAI::new(initial_state.cost_coeff_version);
-                ret = merge_ai.solve(image, initial_state);
+                ret = merge_ai.solve(image, initial_state, ctx);
                 self.initial_block_id = Some(merge_ai.merged_block_id());
                 self.initial_state = simulator::simulate_all(
                     &ret,
@@ -86,8 +94,7 @@ impl HeadAI for DpAI {
         }
         self.initial_block = self
             .initial_state
-            .blocks
-            .get(&self.initial_block_id.clone().unwrap())
+            .block(self.initial_block_id.as_ref().unwrap())
             .unwrap()
             .clone();
         self.initial_image = image::Image::new(image.width(), image.height());
@@ -100,7 +107,8 @@ impl HeadAI for DpAI {
             &mut self.initial_image,
         );
 
-        if self.width() < d || self.height() < d {
+        // DP は途中で止められないので、始める前に締め切りを確認する
+        if d == 0 || self.width() < d || self.height() < d || ctx.should_stop() {
             return ret;
         }
         self.x_offsets = (self.initial_block.p.x
@@ -137,6 +145,18 @@ impl HeadAI for DpAI {
         // 画像の色数が sample_color_num より小さいような場合は
         // sampled_color が sample_color_num に満たない
         self.sample_color_num = self.sampled_color.len();
+        self.rect_stats = image::RectStats::new_in_rect(
+            image,
+            &self.sampled_color,
+            self.initial_block.p,
+            self.initial_block.size,
+        );
+        self.initial_distance = image::image_distance_map(
+            &self.initial_image,
+            image,
+            self.initial_block.p,
+            self.initial_block.size,
+        );
 
         // dp
         let _score = self.calc(0, 0, d, d, 0);
@@ -154,6 +174,7 @@ impl DpAI {
         sample_color_num: usize,
         k_means_iter_num: usize,
         initial_block_id: Option<BlockId>,
+        seed: u64,
     ) -> Self {
         let memo = vec![
             vec![
@@ -170,25 +191,20 @@ impl DpAI {
                 ];
                 sample_color_num + 1
             ];
-        let similality_memo = vec![vec![vec![None; divide_num]; divide_num]; sample_color_num + 1];
         DpAI {
             divide_num: divide_num,
-            rng: rand::thread_rng(),
+            rng: SmallRng::seed_from_u64(seed),
             sample_color_num,
             k_means_iter_num,
             sampled_color: vec![],
             memo,
             memo_restore,
-            similality_memo,
+            rect_stats: image::RectStats::new(&image::Image::new(1, 1), &[]),
+            initial_distance: image::PrefixSum::new(Point::ZERO, Point::ZERO, |_, _| 0.0),
             target_image: image::Image::new(1, 1),
             initial_state: State::initial_state(0, 0, 0),
             initial_block_id: initial_block_id,
-            initial_block: SimpleBlock {
-                p: Point::new(0, 0),
-                size: Point::new(0, 0),
-                color: Color::ONE,
-                state: BlockState::Active,
-            },
+            initial_block: Block::new(Point::new(0, 0), Point::new(0, 0), Color::ONE),
             initial_image: image::Image::new(1, 1),
             x_offsets: vec![],
             y_offsets: vec![],
@@ -392,40 +408,15 @@ impl DpAI {
         }
     }
 
-    fn calc_similality(&mut self, x: usize, y: usize, w: usize, h: usize, color_id: usize) -> i32 {
-        let mut ret = 0;
-        for dx in 0..w {
-            let nx = x + dx;
-            for dy in 0..h {
-                let ny = y + dy;
-                if let Some(s) = self.similality_memo[color_id][nx][ny] {
-                    ret += s;
-                } else {
-                    // TODO
-                    let lt = self.convert_point(nx, ny);
-                    let rb = self.convert_point(nx + 1, ny + 1);
-                    let size = Point::new(rb.x - lt.x, rb.y - lt.y);
-                    let s = if self.sampled_color[color_id] != INVALID_COLOR {
-                        simulator::calc_partial_one_color_similarity(
-                            lt,
-                            size,
-                            self.sampled_color[color_id],
-                            &self.target_image,
-                        ) as i32
-                    } else {
-                        simulator::calc_partial_image_similarity(
-                            lt,
-                            size,
-                            &self.initial_image,
-                            &self.target_image,
-                        ) as i32
-                    };
-                    self.similality_memo[color_id][nx][ny] = Some(s);
-                    ret += s;
-                }
-            }
+    fn calc_similality(&self, x: usize, y: usize, w: usize, h: usize, color_id: usize) -> i32 {
+        let lt = self.convert_point(x, y);
+        let rb = self.convert_point(x + w, y + h);
+        let size = rb - lt;
+        if self.sampled_color[color_id] != INVALID_COLOR {
+            self.rect_stats.similarity(color_id, lt, size) as i32
+        } else {
+            image::distance_to_similarity(self.initial_distance.sum(lt, size)) as i32
         }
-        return ret;
     }
     fn convert_point(&self, x: usize, y: usize) -> Point {
         return Point::new(self.x_offsets[x], self.y_offsets[y]);
@@ -453,22 +444,16 @@ impl DpAI {
 
 #[test]
 fn dp_ai_test() {
-    let mut blocks = std::collections::HashMap::new();
-    let block_id = BlockId(smallvec![0, 0, 0, 2]);
-    let simpel_block = SimpleBlock::new(Point::new(1, 1), Point::new(3, 2), Color::ONE);
-    blocks.insert(block_id, simpel_block);
-    let state = State {
-        blocks,
-        next_global_id: 10,
-        cost_coeff_version: 0,
-    };
+    let simpel_block = Block::new(Point::new(1, 1), Point::new(3, 2), Color::ONE);
+    let mut state = State::new(0, None);
+    state.add_root_block(simpel_block);
     let image = image::Image::from_string_array(&[
         "rr.....", "bbggg..", "bbggg..", "bbggg..", "bbggg..", "bbggg..", "bbggg..", "bbggg..",
         "bbggg..",
     ]);
-    let mut dp_ai = DpAI::new(2, 3, 20, None);
+    let mut dp_ai = DpAI::new(2, 3, 20, None, 0);
 
-    let dp_program = dp_ai.solve(&image, &state);
+    let dp_program = dp_ai.solve(&image, &state, &SolveContext::default());
     assert!(dp_ai.convert_point(0, 0) == Point::new(1, 1));
     assert!(dp_ai.convert_point(2, 2) == Point::new(4, 3));
 
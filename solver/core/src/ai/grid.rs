@@ -1,6 +1,7 @@
 use smallvec::smallvec;
 
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
@@ -11,7 +12,12 @@ pub struct GridAI {
 }
 
 impl HeadAI for GridAI {
-    fn solve(&mut self, image: &image::Image, _initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        _initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         let height = image.height();
         let width = image.width();
 
@@ -54,7 +60,7 @@ impl HeadAI for GridAI {
                 let mut sum = isl::Color::ZERO;
                 for y in y_from..y_to {
                     for x in x_from..x_to {
-                        sum += image.0[y as usize][x as usize];
+                        sum += image.get(x as usize, y as usize);
                     }
                 }
                 result.push(isl::Move::Color {
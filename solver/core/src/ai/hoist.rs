@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::ai::ChainedAI;
+use crate::context::SolveContext;
+use crate::image::Image;
+use crate::isl::*;
+use crate::optimizer;
+use crate::simulator::{self, State};
+
+// 子孫で多く塗られている色を、cut する前の祖先のブロックに1回だけ塗っておく。
+// move cost は面積に反比例するので、大きいブロックを塗る方が安い。
+// 子孫への同じ色の color は不要になるので eliminate_dead_moves で消す
+pub struct HoistAI {
+    // 1つのブロックで試す色の数
+    pub n_candidates: usize,
+}
+
+impl ChainedAI for HoistAI {
+    fn solve(
+        &mut self,
+        image: &Image,
+        initial_state: &State,
+        program: &Program,
+        ctx: &SolveContext,
+    ) -> Program {
+        let w = image.width();
+        let h = image.height();
+        let mut program = optimizer::eliminate_dead_moves(program, initial_state, w, h);
+        let initial_score = simulator::calc_score(&program, image, initial_state).unwrap();
+
+        // 先に cut される (大きい) ブロックから試す
+        let cut_block_ids = program
+            .0
+            .iter()
+            .filter_map(|mv| match mv {
+                Move::PCut { block_id, .. } | Move::LCut { block_id, .. } => Some(block_id.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for block_id in cut_block_ids.iter() {
+            if ctx.should_stop() {
+                break;
+            }
+            if let Some(next_program) = self.hoist(image, initial_state, &program, block_id) {
+                program = next_program;
+            }
+        }
+
+        let score = simulator::calc_score(&program, image, initial_state).unwrap();
+        log::info!("Hoist: {} -> {}", initial_score, score);
+        program
+    }
+}
+
+impl HoistAI {
+    // block_id の cut の直前に色を塗ってみて、一番良くなるものを返す
+    fn hoist(
+        &self,
+        image: &Image,
+        initial_state: &State,
+        program: &Program,
+        block_id: &BlockId,
+    ) -> Option<Program> {
+        let w = image.width();
+        let h = image.height();
+        let mut state = initial_state.clone();
+        let mut cut_index = None;
+        let mut block = None;
+        // 子孫を塗る色ごとの move cost の合計
+        let mut color_costs = HashMap::<[u32; 4], (Color, i64)>::new();
+        for (i, mv) in program.0.iter().enumerate() {
+            let cost = simulator::move_cost(&state, mv, w, h).ok()?;
+            match mv {
+                Move::PCut { block_id: id, .. } | Move::LCut { block_id: id, .. }
+                    if id == block_id =>
+                {
+                    cut_index = Some(i);
+                    block = Some(state.block(id)?.clone());
+                }
+                Move::Color {
+                    block_id: id,
+                    color,
+                } if optimizer::is_descendant(id, block_id) => {
+                    let key = (*color * 255.0).round().to_array().map(|c| c as u32);
+                    color_costs.entry(key).or_insert((*color, 0)).1 += cost;
+                }
+                // 中身が動くブロックが含まれる部分木は触らない
+                Move::Swap { a, b } | Move::Merge { a, b }
+                    if a.0.starts_with(&block_id.0) || b.0.starts_with(&block_id.0) =>
+                {
+                    return None;
+                }
+                _ => {}
+            }
+            simulator::simulate(&mut state, mv).ok()?;
+        }
+        let cut_index = cut_index?;
+        let block = block?;
+
+        // HashMap の順番によらないように、コストが同じなら色で並べる
+        let mut candidates = color_costs.into_iter().collect::<Vec<_>>();
+        candidates.sort_by_key(|&(key, (_, cost))| (-cost, key));
+        candidates.truncate(self.n_candidates);
+
+        let partial_score = |program: &Program| {
+            simulator::calc_partial_score(program, image, block.p, block.size, initial_state)
+                .unwrap()
+        };
+        let mut best_score = partial_score(program);
+        let mut best_program = None;
+        for (_, (color, _)) in candidates {
+            let mut next_program = program.clone();
+            next_program.0.insert(
+                cut_index,
+                Move::Color {
+                    block_id: block_id.clone(),
+                    color,
+                },
+            );
+            let next_program = optimizer::eliminate_dead_moves(&next_program, initial_state, w, h);
+            let score = partial_score(&next_program);
+            if score < best_score {
+                best_score = score;
+                best_program = Some(next_program);
+            }
+        }
+        best_program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hoist() {
+        #[rustfmt::skip]
+        let image = Image::from_string_array(&[
+            "rrrrrrrr",
+            "rrrrrrrr",
+            "rrrrrrrr",
+            "rrrrrrrr",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+        ]);
+        let initial_state = State::initial_state(8, 8, 0);
+        let program: Program = "cut [0] [4, 4]\n\
+                                color [0.0] [255, 0, 0, 255]\n\
+                                color [0.1] [255, 0, 0, 255]\n\
+                                color [0.2] [0, 0, 255, 255]\n\
+                                color [0.3] [255, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        let hoisted = HoistAI { n_candidates: 3 }.solve(
+            &image,
+            &initial_state,
+            &program,
+            &SolveContext::default(),
+        );
+        let expected: Program = "color [0] [255, 0, 0, 255]\n\
+                                 cut [0] [4, 4]\n\
+                                 color [0.2] [0, 0, 255, 255]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(expected, hoisted);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl::*;
 use crate::simulator;
@@ -9,17 +10,21 @@ pub struct MergeAI {
 }
 
 impl HeadAI for MergeAI {
-    fn solve(&mut self, _image: &image::Image, initial_state: &simulator::State) -> Program {
+    fn solve(
+        &mut self,
+        _image: &image::Image,
+        initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> Program {
         self.state = initial_state.clone();
         let mut ret = Program(vec![]);
         let mut total_move_cost = 0;
         while self.active_block_num() > 1 {
             // 左上から順にマージする
-            let blocks = self.state.blocks.clone();
-            let mut blocks = blocks
-                .iter()
-                .filter(|(_key, value)| value.state.is_active())
-                .map(|(key, value)| (key, value))
+            let mut blocks = self
+                .state
+                .active_blocks()
+                .map(|(handle, block)| (self.state.block_id(handle), block.clone()))
                 .collect::<Vec<_>>();
             blocks.sort_by(|a, b| a.1.p.x.cmp(&b.1.p.x).then(a.1.p.y.cmp(&b.1.p.y)));
             let mut target = None;
@@ -63,27 +68,13 @@ impl MergeAI {
             state: State::initial_state(0, 0, cost_coeff_version),
         }
     }
-    fn active_block_num(&self) -> u32 {
-        let mut ret = 0;
-        for block in self.state.blocks.values() {
-            if block.state.is_active() {
-                ret += 1;
-            }
-        }
-        return ret;
+    fn active_block_num(&self) -> usize {
+        self.state.active.len()
     }
     // 最終的に全部まとめ終わった時のblock_id
     #[allow(dead_code)]
     pub fn merged_block_id(&self) -> BlockId {
         assert!(self.active_block_num() == 1);
-        return self
-            .state
-            .blocks
-            .iter()
-            .filter(|(_, value)| value.state.is_active())
-            .next()
-            .unwrap()
-            .0
-            .clone();
+        return self.state.block_id(self.state.active[0]);
     }
 }
@@ -1,10 +1,14 @@
 mod annealing;
 mod changecolor;
 mod cross;
+mod deadmove;
 mod dp;
 mod grid;
+mod hoist;
 mod merge;
 mod onecolor;
+mod prune;
+mod recolor;
 mod rect;
 mod refine;
 mod swap;
@@ -12,20 +16,31 @@ mod swap;
 pub use annealing::*;
 pub use changecolor::*;
 pub use cross::*;
+pub use deadmove::*;
 pub use dp::*;
 pub use grid::*;
+pub use hoist::*;
 pub use merge::*;
 pub use onecolor::*;
+pub use prune::*;
+pub use recolor::*;
 pub use rect::*;
 pub use refine::*;
 pub use swap::*;
 
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
 
+// ctx はパイプライン全体で共有する。時間のかかる AI は ctx.should_stop() で打ち切る
 pub trait HeadAI {
-    fn solve(&mut self, image: &image::Image, initial_state: &simulator::State) -> isl::Program;
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        initial_state: &simulator::State,
+        ctx: &SolveContext,
+    ) -> isl::Program;
 }
 
 pub trait ChainedAI {
@@ -34,5 +49,6 @@ pub trait ChainedAI {
         image: &image::Image,
         initial_state: &simulator::State,
         program: &isl::Program,
+        ctx: &SolveContext,
     ) -> isl::Program;
 }
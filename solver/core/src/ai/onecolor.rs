@@ -1,4 +1,5 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
@@ -6,13 +7,16 @@ use crate::simulator;
 pub struct OneColorAI {}
 
 impl HeadAI for OneColorAI {
-    fn solve(&mut self, image: &image::Image, _initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        _initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         let mut sum = glam::Vec4::ZERO;
 
-        for row in &image.0 {
-            for color in row {
-                sum += *color;
-            }
+        for &c in image.pixels() {
+            sum += image::from_rgba(c);
         }
 
         let color = sum / image.area() as f32;
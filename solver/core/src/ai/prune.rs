@@ -0,0 +1,258 @@
+use crate::ai::ChainedAI;
+use crate::context::SolveContext;
+use crate::image::{self, Image};
+use crate::isl::*;
+use crate::simulator::{self, Block, BlockHandle, State};
+
+// プログラムの cut の木について、各部分木を
+//   Keep:     そのまま残す
+//   Truncate: 根の cut と子孫への手を全て消す (根は cut 直前の状態のまま残る)
+//   Recolor:  さらに根への color も消して、距離の和が最小になる色で1回だけ塗る
+// のどれにするかを、move cost + similarity が最小になるように木 DP で決める。
+// swap, merge で使われるブロックとその祖先はそのまま残す。
+// 部分木ごとに丸める前の距離の和を足すので、丸めを除けば最適な組み合わせになる
+pub struct PruneAI {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Choice {
+    Keep,
+    Truncate,
+    Recolor,
+}
+
+impl ChainedAI for PruneAI {
+    fn solve(
+        &mut self,
+        image: &Image,
+        initial_state: &State,
+        program: &Program,
+        _ctx: &SolveContext,
+    ) -> Program {
+        let pruned = match prune(image, initial_state, program) {
+            Some(pruned) => pruned,
+            None => return program.clone(),
+        };
+        let score = simulator::calc_score(program, image, initial_state).unwrap();
+        let pruned_score = simulator::calc_score(&pruned, image, initial_state).unwrap();
+        log::info!(
+            "Prune: {} moves ({}) -> {} moves ({})",
+            program.len(),
+            score,
+            pruned.len(),
+            pruned_score
+        );
+        if pruned_score < score {
+            pruned
+        } else {
+            program.clone()
+        }
+    }
+}
+
+fn prune(image: &Image, initial_state: &State, program: &Program) -> Option<Program> {
+    let w = image.width();
+    let h = image.height();
+    let mut state = initial_state.clone();
+    // handle ごとの情報。ブロックは作られた順に並ぶので、子の handle は親より大きい
+    let mut own_cost = vec![0; state.blocks.len()];
+    let mut color_cost = vec![0; state.blocks.len()];
+    let mut created_at = vec![0; state.blocks.len()];
+    let mut cut_at: Vec<Option<(usize, Block)>> = vec![None; state.blocks.len()];
+    let mut fixed = vec![false; state.blocks.len()];
+    // 何手目がどのブロックへの手か (swap, merge は None)
+    let mut move_owner = vec![];
+    for (i, mv) in program.0.iter().enumerate() {
+        let cost = simulator::move_cost(&state, mv, w, h).ok()?;
+        let owner = match mv {
+            Move::PCut { block_id, .. } | Move::LCut { block_id, .. } => {
+                let handle = state.handle(block_id)?;
+                cut_at[handle as usize] = Some((i, state.blocks[handle as usize].clone()));
+                own_cost[handle as usize] += cost;
+                Some(handle)
+            }
+            Move::Color { block_id, .. } => {
+                let handle = state.handle(block_id)?;
+                own_cost[handle as usize] += cost;
+                color_cost[handle as usize] += cost;
+                Some(handle)
+            }
+            Move::Swap { a, b } | Move::Merge { a, b } => {
+                fixed[state.handle(a)? as usize] = true;
+                fixed[state.handle(b)? as usize] = true;
+                None
+            }
+        };
+        move_owner.push(owner);
+        simulator::simulate(&mut state, mv).ok()?;
+        own_cost.resize(state.blocks.len(), 0);
+        color_cost.resize(state.blocks.len(), 0);
+        created_at.resize(state.blocks.len(), i + 1);
+        cut_at.resize(state.blocks.len(), None);
+        fixed.resize(state.blocks.len(), false);
+    }
+    let n = state.blocks.len();
+    for handle in (0..n).rev() {
+        if let Some(parent) = state.blocks[handle].parent {
+            fixed[parent as usize] |= fixed[handle];
+        }
+    }
+
+    let final_image = simulator::rasterize_state(&state, w, h);
+    let mut scratch = Image::new(w, h);
+    // 部分木の move cost と、丸める前の similarity (距離の和 * 0.005) の和
+    let mut best = vec![0.0; n];
+    let mut choice = vec![Choice::Keep; n];
+    let mut recolor = vec![Color::ZERO; n];
+    for handle in (0..n).rev() {
+        let block = &state.blocks[handle];
+        if block.children.is_empty() && !block.state.is_active() {
+            // merge で使われたブロック
+            continue;
+        }
+        best[handle] = own_cost[handle] as f64
+            + if block.children.is_empty() {
+                image_distance(block.p, block.size, &final_image, image) * 0.005
+            } else {
+                block.children.iter().map(|&c| best[c as usize]).sum()
+            };
+        if fixed[handle] {
+            continue;
+        }
+        if let Some((_, ref block_at_cut)) = cut_at[handle] {
+            let cost = color_cost[handle] as f64
+                + block_distance(block_at_cut, &state, image, &mut scratch) * 0.005;
+            if cost < best[handle] {
+                best[handle] = cost;
+                choice[handle] = Choice::Truncate;
+            }
+        }
+        let pixels = image
+            .rect_rows(block.p, block.size)
+            .flatten()
+            .map(|&pixel| image::from_rgba(pixel))
+            .collect::<Vec<_>>();
+        let color = image::best_integer_color(&pixels);
+        let color_move = Move::Color {
+            block_id: BlockId::new(&[0]),
+            color,
+        };
+        let cost = simulator::move_cost_without_state(
+            &color_move,
+            block.area() as usize,
+            w,
+            h,
+            state.cost_coeff_version,
+        ) as f64
+            + image::total_distance(&pixels, color) * 0.005;
+        if cost < best[handle] {
+            best[handle] = cost;
+            choice[handle] = Choice::Recolor;
+            recolor[handle] = color;
+        }
+    }
+
+    // 根から決めた選択を適用する
+    let mut removed = vec![false; program.len()];
+    let mut inserted: Vec<Vec<Move>> = vec![vec![]; program.len() + 1];
+    // 部分木ごと消すブロック
+    let mut dropped = vec![false; n];
+    for handle in 0..n {
+        if let Some(parent) = state.blocks[handle].parent {
+            if dropped[parent as usize] || choice[parent as usize] != Choice::Keep {
+                dropped[handle] = true;
+                continue;
+            }
+        }
+        match choice[handle] {
+            Choice::Keep => {}
+            Choice::Truncate => {
+                removed[cut_at[handle].as_ref().unwrap().0] = true;
+            }
+            Choice::Recolor => {
+                inserted[created_at[handle]].push(Move::Color {
+                    block_id: state.block_id(handle as BlockHandle),
+                    color: recolor[handle],
+                });
+            }
+        }
+    }
+    for (i, owner) in move_owner.iter().enumerate() {
+        if let Some(owner) = owner {
+            let owner = *owner as usize;
+            if dropped[owner] || choice[owner] == Choice::Recolor {
+                removed[i] = true;
+            }
+        }
+    }
+
+    let mut ret = vec![];
+    for (i, mv) in program.0.iter().enumerate() {
+        ret.append(&mut inserted[i]);
+        if !removed[i] {
+            ret.push(mv.clone());
+        }
+    }
+    ret.append(&mut inserted[program.len()]);
+    Some(Program(ret))
+}
+
+// block をそのまま残したときの距離の和
+fn block_distance(block: &Block, state: &State, image: &Image, scratch: &mut Image) -> f64 {
+    if block.is_flat() {
+        let color = image::to_rgba(block.color);
+        return image
+            .rect_rows(block.p, block.size)
+            .flatten()
+            .map(|&pixel| image::pixel_distance(color, pixel))
+            .sum();
+    }
+    block.partial_rasterize(block.p, block.size, state.source_image.as_deref(), scratch);
+    image_distance(block.p, block.size, scratch, image)
+}
+
+// 2つの画像の [p, p + size) の距離の和 (calc_partial_image_similarity の丸める前)
+fn image_distance(p: Point, size: Point, a: &Image, b: &Image) -> f64 {
+    a.rect_rows(p, size)
+        .flatten()
+        .zip(b.rect_rows(p, size).flatten())
+        .map(|(&a, &b)| image::pixel_distance(a, b))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        #[rustfmt::skip]
+        let image = Image::from_string_array(&[
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbbb",
+            "rrrrbbgb",
+        ]);
+        let initial_state = State::initial_state(8, 8, 0);
+        // 右側の細かい cut は1ピクセルのためにコストをかけすぎている
+        let program: Program = "cut [0] [X] [4]\n\
+                                color [0.0] [255, 0, 0, 255]\n\
+                                cut [0.1] [6, 7]\n\
+                                color [0.1.0] [0, 0, 255, 255]\n\
+                                color [0.1.1] [0, 0, 255, 255]\n\
+                                color [0.1.2] [0, 0, 255, 255]\n\
+                                color [0.1.3] [0, 255, 0, 255]\n"
+            .parse()
+            .unwrap();
+        let pruned = PruneAI {}.solve(&image, &initial_state, &program, &SolveContext::default());
+        let expected: Program = "cut [0] [X] [4]\n\
+                                 color [0.1] [0, 0, 255, 255]\n\
+                                 color [0.0] [255, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(expected, pruned);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::ai::ChainedAI;
+use crate::context::SolveContext;
+use crate::image::{self, Image, Rgba};
+use crate::isl::*;
+use crate::simulator::{self, State};
+
+// 全ての color の色を、その手で塗られて最後まで見えているピクセルの
+// 幾何中央値 (を整数に丸めて近くを探したもの) に塗り替える
+pub struct RecolorAI {}
+
+// i 手目の color の代わりに塗る色。i を RGBA の4バイトに埋め込む。
+// 元画像のピクセルと区別するために、ビットを反転した色でも描いて両方が合うかを見る
+fn tag(i: usize, inverted: bool) -> Color {
+    let i = if inverted { !(i as u32) } else { i as u32 };
+    image::from_rgba(i.to_le_bytes())
+}
+
+fn untag(c: Rgba, inverted_c: Rgba) -> Option<usize> {
+    let i = u32::from_le_bytes(c);
+    if i == !u32::from_le_bytes(inverted_c) {
+        Some(i as usize)
+    } else {
+        None
+    }
+}
+
+impl ChainedAI for RecolorAI {
+    fn solve(
+        &mut self,
+        image: &Image,
+        initial_state: &State,
+        program: &Program,
+        _ctx: &SolveContext,
+    ) -> Program {
+        let w = image.width();
+        let h = image.height();
+        let render_tagged = |inverted: bool| {
+            let mut tagged = program.clone();
+            for (i, mv) in tagged.0.iter_mut().enumerate() {
+                if let Move::Color { color, .. } = mv {
+                    *color = tag(i, inverted);
+                }
+            }
+            simulator::simulate_all(&tagged, initial_state, w, h)
+                .ok()
+                .map(|(state, _)| simulator::rasterize_state(&state, w, h))
+        };
+        let (tagged_image, inverted_image) = match (render_tagged(false), render_tagged(true)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return program.clone(),
+        };
+        let mut pixels = vec![vec![]; program.len()];
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(i) = untag(tagged_image.rgba(x, y), inverted_image.rgba(x, y)) {
+                    pixels[i].push(image.get(x, y));
+                }
+            }
+        }
+
+        let mut ret = program.clone();
+        let mut n_updated = 0;
+        for (mv, pixels) in ret.0.iter_mut().zip(pixels.iter()) {
+            if let Move::Color { color, .. } = mv {
+                if pixels.is_empty() {
+                    continue;
+                }
+                let next_color = image::best_integer_color(pixels);
+                if image::total_distance(pixels, next_color) < image::total_distance(pixels, *color)
+                {
+                    *color = next_color;
+                    n_updated += 1;
+                }
+            }
+        }
+        log::info!("Recolor: {n_updated} colors updated");
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recolor() {
+        #[rustfmt::skip]
+        let image = Image::from_string_array(&[
+            "rrrrgggg",
+            "rrrrgggg",
+            "rrbrgggg",
+            "rrrrgggg",
+        ]);
+        let initial_state = State::initial_state(8, 4, 0);
+        // [0] の色は [0.1] で上書きされない左側だけで決まる
+        let program: Program = "color [0] [0, 0, 0, 255]\n\
+                                cut [0] [X] [4]\n\
+                                color [0.1] [0, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        let recolored =
+            RecolorAI {}.solve(&image, &initial_state, &program, &SolveContext::default());
+        let expected: Program = "color [0] [255, 0, 0, 255]\n\
+                                 cut [0] [X] [4]\n\
+                                 color [0.1] [0, 255, 0, 255]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(expected, recolored);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
@@ -14,7 +15,12 @@ struct Area {
 }
 
 impl HeadAI for RectAI {
-    fn solve(&mut self, image: &image::Image, _initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        _initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         let height = image.height();
         let width = image.width();
 
@@ -45,7 +51,7 @@ impl HeadAI for RectAI {
                 let mut size = 0;
                 let mut min = isl::Point::new(width as i32, height as i32);
                 let mut max = isl::Point::ZERO;
-                let color = image.0[i][j];
+                let color = image.get(j, i);
                 let mut sum_color = isl::Color::ZERO;
 
                 let mut que = vec![isl::Point::new(j as i32, i as i32)];
@@ -56,7 +62,7 @@ impl HeadAI for RectAI {
                     }
 
                     size += 1;
-                    sum_color += image.0[cur.y as usize][cur.x as usize];
+                    sum_color += image.get(cur.x as usize, cur.y as usize);
                     visited[cur.y as usize][cur.x as usize] = true;
                     min.x = std::cmp::min(min.x, cur.x as i32);
                     min.y = std::cmp::min(min.y, cur.y as i32);
@@ -72,7 +78,7 @@ impl HeadAI for RectAI {
                                 && next.y < height as i32
                                 && 0 <= next.x
                                 && next.x < width as i32
-                                && (image.0[next.y as usize][next.x as usize] - color).length()
+                                && (image.get(next.x as usize, next.y as usize) - color).length()
                                     < 0.1
                             {
                                 que.push(next);
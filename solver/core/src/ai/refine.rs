@@ -1,6 +1,8 @@
 use crate::ai;
+use crate::context::SolveContext;
 use crate::image;
-use crate::image::Image;
+use crate::image::{Image, RectStats};
+use crate::incremental::{IncrementalSimulator, CHECKPOINT_INTERVAL};
 use crate::isl::*;
 use crate::simulator;
 use crate::simulator::calc_partial_image_similarity;
@@ -9,7 +11,8 @@ use crate::simulator::rasterize_state;
 use crate::simulator::simulate_all;
 use crate::simulator::State;
 use log::info;
-use rand::Rng;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
 
 use super::HeadAI;
 
@@ -24,6 +27,7 @@ pub struct RefineAi {
     pub initial_temperature: f64,
     pub dp_divide_max: usize,
     pub show_intermediates: bool,
+    pub seed: u64,
 }
 
 impl ai::ChainedAI for RefineAi {
@@ -32,9 +36,9 @@ impl ai::ChainedAI for RefineAi {
         image: &image::Image,
         initial_state: &State,
         initial_program: &Program,
+        ctx: &SolveContext,
     ) -> Program {
-        // TODO seed_from_u64
-        let mut rng = rand::thread_rng();
+        let mut rng = SmallRng::seed_from_u64(self.seed);
 
         let mut prev_program = initial_program.clone();
         let mut current_score =
@@ -46,8 +50,18 @@ impl ai::ChainedAI for RefineAi {
         let mut best_program = initial_program.clone();
         let mut best_score = current_score;
 
+        // 候補ごとに変更された手以降だけを再計算する
+        let mut sim = IncrementalSimulator::new(
+            initial_state,
+            image.width(),
+            image.height(),
+            CHECKPOINT_INTERVAL,
+        );
+
         let mut temperature;
 
+        let stats = RectStats::new(image, &[]);
+
         // newが遅いので使いまわす
         let mut candidate_partial_image = Image::new(image.width(), image.height());
 
@@ -56,7 +70,7 @@ impl ai::ChainedAI for RefineAi {
             let progress = (iter as f64) / (self.n_iters as f64);
             temperature = self.initial_temperature * (1.0 - progress) * (-progress).exp2();
 
-            if prev_program.0.len() == 0 {
+            if prev_program.0.len() == 0 || ctx.should_stop() {
                 break;
             }
             let (candidate_program, lt, size, description) = match self.make_neighbor(
@@ -64,17 +78,15 @@ impl ai::ChainedAI for RefineAi {
                 image,
                 initial_state,
                 &current_end_state,
+                &stats,
                 &mut rng,
+                ctx,
             ) {
                 Some(x) => x,
                 None => continue,
             };
-            let (candidate_end_state, candidate_move_score) = match simulate_all(
-                &candidate_program,
-                initial_state,
-                image.width(),
-                image.height(),
-            ) {
+            let (candidate_end_state, candidate_move_score) = match sim.simulate(&candidate_program)
+            {
                 Ok(result) => result,
                 Err(_err) => {
                     // assert!(!description.contains("DpAI"));
@@ -85,7 +97,7 @@ impl ai::ChainedAI for RefineAi {
             rasterize_parital_state(
                 lt,
                 size,
-                &candidate_end_state,
+                candidate_end_state,
                 image.width(),
                 image.height(),
                 &mut candidate_partial_image,
@@ -120,15 +132,9 @@ impl ai::ChainedAI for RefineAi {
 
             if accept {
                 prev_program = candidate_program;
-                (current_end_state, current_move_score) =
-                    simulate_all(&prev_program, initial_state, image.width(), image.height())
-                        .unwrap();
-                for y in lt.y..(lt.y + size.y) {
-                    for x in lt.x..(lt.x + size.x) {
-                        current_image.0[y as usize][x as usize] =
-                            candidate_partial_image.0[y as usize][x as usize];
-                    }
-                }
+                current_end_state = sim.state().clone();
+                current_move_score = sim.cost();
+                current_image.copy_rect(&candidate_partial_image, lt, lt, size);
                 // round分の誤差が出るので計算しなおす
                 current_score = current_move_score
                     + calc_partial_image_similarity(
@@ -150,6 +156,7 @@ impl ai::ChainedAI for RefineAi {
                     best_score = new_score;
                     best_program = prev_program.clone();
                 }
+                ctx.report(&prev_program, current_score);
             }
 
             if self.show_intermediates && iter % 50 == 0 {
@@ -165,13 +172,16 @@ impl ai::ChainedAI for RefineAi {
 
 impl RefineAi {
     // 近傍解を作る
+    #[allow(clippy::too_many_arguments)]
     fn make_neighbor(
         &self,
         prev_program: &Program,
         image: &Image,
         initial_state: &State,
         end_state: &State,
+        stats: &RectStats,
         rng: &mut impl Rng,
+        ctx: &SolveContext,
     ) -> Option<(Program, Point, Point, String)> {
         let description;
         let mut next_program = prev_program.clone();
@@ -180,10 +190,10 @@ impl RefineAi {
         if rng.gen_range(0..100) == 0 {
             // 1/100 の確率でランダムにDpAIで分割する
             let block_id = end_state.sample_active_block(rng);
-            let tl = end_state.blocks[&block_id].p;
-            let size = end_state.blocks[&block_id].size;
+            let tl = end_state.block(&block_id).unwrap().p;
+            let size = end_state.block(&block_id).unwrap().size;
             let mut next_program =
-                self.solve_by_dp_ai_one_block(next_program, &block_id, image, end_state, rng);
+                self.solve_by_dp_ai_one_block(next_program, &block_id, image, end_state, rng, ctx);
             if prev_program.len() == next_program.len() {
                 return None;
             }
@@ -198,7 +208,7 @@ impl RefineAi {
                 ref block_id,
                 point,
             } => {
-                let block = end_state.blocks[block_id];
+                let block = end_state.block(block_id).unwrap();
                 tl = block.p;
                 size = block.size;
                 let r = rng.gen_range(0..4);
@@ -240,6 +250,7 @@ impl RefineAi {
                             image,
                             &next_end_state,
                             rng,
+                            ctx,
                         );
                         next_program.remove_redundant_color_move();
                         description = format!("Remove PCut & divide by DpAI");
@@ -253,7 +264,7 @@ impl RefineAi {
                 orientation,
                 line_number,
             } => {
-                let block = end_state.blocks[block_id];
+                let block = end_state.block(block_id).unwrap();
                 tl = block.p;
                 size = block.size;
                 let r = rng.gen_range(0..4);
@@ -293,6 +304,7 @@ impl RefineAi {
                             image,
                             &next_end_state,
                             rng,
+                            ctx,
                         );
                         next_program.remove_redundant_color_move();
                         description = format!("Remove LCut & divide by DpAI");
@@ -305,7 +317,7 @@ impl RefineAi {
                 ref block_id,
                 color: prev_color,
             } => {
-                let block = end_state.blocks[block_id];
+                let block = end_state.block(block_id).unwrap();
                 tl = block.p;
                 size = block.size;
                 let r = rng.gen_range(0..5);
@@ -314,10 +326,10 @@ impl RefineAi {
                         // random sampling
                         let x = block.p.x + rng.gen_range(0..block.size.x);
                         let y = block.p.y + rng.gen_range(0..block.size.y);
-                        image.0[y as usize][x as usize]
+                        image.get(x as usize, y as usize)
                     } else {
                         // average
-                        image.average(block.p, block.size)
+                        stats.average(block.p, block.size)
                     };
                     let d = prev_color - color;
                     let similarity = (d * 255.0).round().length() as f64;
@@ -356,12 +368,13 @@ impl RefineAi {
         image: &Image,
         end_state: &State,
         rng: &mut impl Rng,
+        ctx: &SolveContext,
     ) -> Program {
         let mut program = program;
         let d = rng.gen_range(4..=self.dp_divide_max);
         let c = rng.gen_range(3..=8);
-        let mut dp_ai = ai::DpAI::new(d, c, 10, Some(block_id.clone()));
-        let mut dp_program = dp_ai.solve(image, &end_state);
+        let mut dp_ai = ai::DpAI::new(d, c, 10, Some(block_id.clone()), rng.gen());
+        let mut dp_program = dp_ai.solve(image, &end_state, ctx);
         program.0.append(&mut dp_program.0);
         program.remove_redundant_color_move();
         return program;
@@ -1,6 +1,7 @@
 use log::info;
 
 use crate::ai::HeadAI;
+use crate::context::SolveContext;
 use crate::image;
 use crate::isl;
 use crate::simulator;
@@ -8,19 +9,38 @@ use crate::simulator;
 pub struct SwapAI {}
 
 impl HeadAI for SwapAI {
-    fn solve(&mut self, image: &image::Image, initial_state: &simulator::State) -> isl::Program {
+    fn solve(
+        &mut self,
+        image: &image::Image,
+        initial_state: &simulator::State,
+        _ctx: &SolveContext,
+    ) -> isl::Program {
         // let mut state = initial_state.clone();
-        let blocks = initial_state.blocks.iter().collect::<Vec<_>>();
+        let blocks = initial_state
+            .active_blocks()
+            .map(|(handle, block)| (initial_state.block_id(handle), block))
+            .collect::<Vec<_>>();
 
         let colors = blocks.iter().map(|b| b.1.color).collect::<Vec<_>>();
+        // 同じ色のブロックが多いので、色の種類ごとに距離の累積和を作る
+        let mut palette: Vec<isl::Color> = vec![];
+        let color_index = colors
+            .iter()
+            .map(|c| match palette.iter().position(|p| p == c) {
+                Some(k) => k,
+                None => {
+                    palette.push(*c);
+                    palette.len() - 1
+                }
+            })
+            .collect::<Vec<_>>();
+        let stats = image::RectStats::new(image, &palette);
         let mut similarity = vec![vec![0; colors.len()]; blocks.len()]; // ブロックiを色jで塗ったときのsimilarity
 
         for i in 0..blocks.len() {
             for j in 0..colors.len() {
                 let block = blocks[i].1;
-                similarity[i][j] = simulator::calc_partial_one_color_similarity(
-                    block.p, block.size, colors[j], image,
-                )
+                similarity[i][j] = stats.similarity(color_index[j], block.p, block.size);
             }
         }
 
@@ -38,8 +58,8 @@ impl HeadAI for SwapAI {
             for i in 0..blocks.len() {
                 for j in (i + 1)..blocks.len() {
                     if blocks[i].1.size == blocks[j].1.size {
-                        let (bid_i, b_i) = blocks[i];
-                        let (bid_j, _) = blocks[j];
+                        let (ref bid_i, b_i) = blocks[i];
+                        let (ref bid_j, _) = blocks[j];
                         let swap_move = isl::Move::Swap {
                             a: bid_i.clone(),
                             b: bid_j.clone(),
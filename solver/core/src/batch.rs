@@ -0,0 +1,253 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context};
+use log::{info, warn};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::db::Recorder;
+use crate::{context, init_logger, parse_ai_string, solve_problem, SolveOpt};
+
+#[derive(Debug, StructOpt)]
+pub struct BatchOpt {
+    // <problems_dir>/N.png を全て解く。N.initial.json があればそれも読む
+    #[structopt(
+        short = "p",
+        long = "problems",
+        default_value = "problems",
+        parse(from_os_str)
+    )]
+    problems_dir: PathBuf,
+
+    #[structopt(
+        short = "j",
+        long = "jobs",
+        help = "number of problems solved in parallel [default: number of CPUs]"
+    )]
+    jobs: Option<usize>,
+
+    #[structopt(flatten)]
+    solve: SolveOpt,
+}
+
+// 1問の結果。summary.csv と summary.json の1行になる
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    problem_id: String,
+    score: Option<i64>,
+    seconds: f64,
+    ai: String,
+    seed: u64,
+    // --keep-best で既存の解を残した (score は既存の解のもの)
+    kept: bool,
+    error: Option<String>,
+}
+
+pub fn batch(opt: BatchOpt) -> anyhow::Result<()> {
+    init_logger(opt.solve.quiet);
+
+    // 全ての問題で同じ seed を使うので、1問だけ run --seed で再現できる
+    let seed = opt.solve.seed.unwrap_or_else(rand::random);
+    info!("seed: {seed}");
+
+    // 全ての問題で同じエラーになるものは先に確かめておく
+    parse_ai_string(&opt.solve, false, seed)?;
+    if !opt.solve.output_dir.is_dir() {
+        bail!(
+            "'{}' is not a directory",
+            opt.solve.output_dir.to_string_lossy()
+        );
+    }
+
+    let problems = list_problems(&opt.problems_dir)?;
+    // 全ての問題を1つの run として記録する
+    let recorder = match &opt.solve.db {
+        Some(path) => Some(Recorder::new(path, opt.solve.run_id.clone())?),
+        None => None,
+    };
+    let run_id = match &recorder {
+        Some(recorder) => Some(recorder.run_id()),
+        None => opt.solve.run_id.as_deref(),
+    };
+    let jobs = match opt.jobs {
+        Some(jobs) => jobs.max(1),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    info!("solve {} problems with {jobs} jobs", problems.len());
+
+    // 止められたら実行中の問題はそれまでの最良解を書き出し、残りの問題は解かない
+    let cancelled = Arc::new(AtomicBool::new(false));
+    context::cancel_on_signal(cancelled.clone())?;
+
+    // 空いたスレッドが次の問題を取る
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    thread::scope(|s| {
+        for _ in 0..jobs.min(problems.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= problems.len() {
+                    break;
+                }
+                let result = solve_one(
+                    &opt.solve,
+                    &problems[i],
+                    seed,
+                    run_id,
+                    &cancelled,
+                    recorder.as_ref(),
+                );
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(i, _)| i);
+    let results = results.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+
+    write_summary(&opt.solve.output_dir, &results)?;
+
+    let total = results.iter().filter_map(|r| r.score).sum::<i64>();
+    let n_failed = results.iter().filter(|r| r.error.is_some()).count();
+    info!(
+        "total score: {total} ({} solved, {n_failed} failed)",
+        results.len() - n_failed
+    );
+    if n_failed > 0 {
+        bail!("{n_failed} of {} problem(s) failed", results.len());
+    }
+    Ok(())
+}
+
+fn solve_one(
+    opt: &SolveOpt,
+    input_path: &Path,
+    seed: u64,
+    run_id: Option<&str>,
+    cancelled: &Arc<AtomicBool>,
+    recorder: Option<&Recorder>,
+) -> BatchResult {
+    let problem_id = input_path
+        .file_stem()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    let start = Instant::now();
+    let output = if cancelled.load(Ordering::Relaxed) {
+        Err(anyhow!("cancelled before start"))
+    } else {
+        solve_problem(opt, input_path, None, seed, run_id, cancelled.clone())
+    };
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs_f64();
+    let (score, kept, error) = match output {
+        Ok(output) => {
+            info!("problem {problem_id}: {} ({seconds:.1} sec)", output.score);
+            // 記録に失敗しても解は書き出せているので、スコアは残す
+            let error = recorder
+                .and_then(|recorder| recorder.record(&output, elapsed).err())
+                .map(|err| format!("failed to record: {err:#}"));
+            if let Some(error) = &error {
+                warn!("problem {problem_id}: {error}");
+            }
+            (Some(output.score), output.kept, error)
+        }
+        Err(err) => {
+            warn!("problem {problem_id}: {err:#}");
+            (None, false, Some(format!("{err:#}")))
+        }
+    };
+    BatchResult {
+        problem_id,
+        score,
+        seconds,
+        ai: opt.ai.clone(),
+        seed,
+        kept,
+        error,
+    }
+}
+
+// dir にある N.png を N の順に並べる
+fn list_problems(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut problems = vec![];
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("failed to read '{}'", dir.to_string_lossy()))?
+    {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("png")) {
+            continue;
+        }
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u32>().ok());
+        if let Some(id) = id {
+            problems.push((id, path));
+        }
+    }
+    if problems.is_empty() {
+        bail!("no N.png in '{}'", dir.to_string_lossy());
+    }
+    problems.sort();
+    Ok(problems.into_iter().map(|(_, path)| path).collect())
+}
+
+// <output_dir>/summary.csv と summary.json に書き出す
+fn write_summary(output_dir: &Path, results: &[BatchResult]) -> anyhow::Result<()> {
+    let mut csv = "problem_id,score,seconds,ai,seed,kept,error\n".to_string();
+    for r in results {
+        let fields = [
+            r.problem_id.clone(),
+            r.score.map_or(String::new(), |score| score.to_string()),
+            format!("{:.3}", r.seconds),
+            r.ai.clone(),
+            r.seed.to_string(),
+            r.kept.to_string(),
+            r.error.clone().unwrap_or_default(),
+        ];
+        let fields = fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>();
+        csv += &fields.join(",");
+        csv.push('\n');
+    }
+    let csv_path = output_dir.join("summary.csv");
+    info!("output summary to: {}", csv_path.to_string_lossy());
+    fs::write(csv_path, csv)?;
+
+    let json_path = output_dir.join("summary.json");
+    info!("output summary to: {}", json_path.to_string_lossy());
+    fs::write(json_path, serde_json::to_string_pretty(results)?)?;
+    Ok(())
+}
+
+// -a の値やエラーメッセージには ',' や改行が入るので、その場合は '"' で囲む
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("12", csv_field("12"));
+        assert_eq!("DP|Refine", csv_field("DP|Refine"));
+        assert_eq!(
+            "\"DP(divide=12,colors=6)\"",
+            csv_field("DP(divide=12,colors=6)")
+        );
+        assert_eq!("\"say \"\"hi\"\"\"", csv_field("say \"hi\""));
+        assert_eq!("\"a\nb\"", csv_field("a\nb"));
+    }
+}
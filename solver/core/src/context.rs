@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::isl::Program;
+
+type Observer = Box<dyn Fn(&Program, i64) + Send + Sync>;
+
+// パイプラインの全段で共有する、打ち切りの条件と途中経過の通知先。
+// 時間のかかる AI はループの中で should_stop() を見て、true なら今までの最良解を返す
+pub struct SolveContext {
+    // パイプライン全体の締め切り
+    deadline: Option<Instant>,
+    // シグナルハンドラなど別スレッドから立てられる
+    cancelled: Arc<AtomicBool>,
+    best_score: AtomicI64,
+    observer: Option<Observer>,
+}
+
+impl Default for SolveContext {
+    fn default() -> Self {
+        SolveContext {
+            deadline: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            best_score: AtomicI64::new(i64::MAX),
+            observer: None,
+        }
+    }
+}
+
+impl SolveContext {
+    pub fn with_time_limit(mut self, time_limit: Duration) -> Self {
+        self.deadline = Some(Instant::now() + time_limit);
+        self
+    }
+
+    pub fn with_cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = cancelled;
+        self
+    }
+
+    // (プログラム, スコア) で、それまでに報告されたどの解よりスコアが良いときだけ呼ばれる
+    pub fn with_observer(
+        mut self,
+        observer: impl Fn(&Program, i64) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // 締め切りまでの残り時間。締め切りがなければ None
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.is_cancelled() || self.remaining() == Some(Duration::ZERO)
+    }
+
+    // 初期状態から実行したときのスコアと一緒に、途中の解を報告する
+    pub fn report(&self, program: &Program, score: i64) {
+        if score >= self.best_score.fetch_min(score, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(observer) = &self.observer {
+            observer(program, score);
+        }
+    }
+}
+
+// Ctrl-C (SIGINT) と SIGTERM で cancelled を立てる。各 AI が止まるのを待てないときのために、
+// 2回目は即座に終了する。プロセスで1回だけ呼べる
+pub fn cancel_on_signal(cancelled: Arc<AtomicBool>) -> anyhow::Result<()> {
+    ctrlc::set_handler(move || {
+        if cancelled.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        log::warn!("interrupted, stopping the current stage (send again to exit immediately)");
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_solve_context() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let ctx = SolveContext::default().with_observer({
+            let reported = reported.clone();
+            move |_, score| reported.lock().unwrap().push(score)
+        });
+        for score in [100, 120, 90, 90, 95, 80] {
+            ctx.report(&Program(vec![]), score);
+        }
+        assert_eq!(vec![100, 90, 80], *reported.lock().unwrap());
+
+        assert!(!ctx.should_stop());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let ctx = SolveContext::default().with_cancel_flag(cancelled.clone());
+        cancelled.store(true, Ordering::Relaxed);
+        assert!(ctx.should_stop());
+
+        let ctx = SolveContext::default().with_time_limit(Duration::ZERO);
+        assert!(ctx.should_stop());
+        let ctx = SolveContext::default().with_time_limit(Duration::from_secs(3600));
+        assert!(!ctx.should_stop());
+    }
+}
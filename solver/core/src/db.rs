@@ -0,0 +1,404 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use log::info;
+use rusqlite::{params, Connection, Row};
+use structopt::StructOpt;
+
+use crate::Output;
+
+// Lambda が書く DynamoDB のテーブルと同じ内容を手元の SQLite に持つ。
+//   PK "R#<run_id>", SK "S#<problem_id>" -> (run_id, problem_id) の主キー
+//   GSI1 "P#<problem_id>" と score       -> results_by_problem
+// S3 に置いていた ISL は program に入れる (PNG は render で作れるので持たない)
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS results (
+    run_id TEXT NOT NULL,
+    problem_id TEXT NOT NULL,
+    score INTEGER NOT NULL,
+    ai TEXT NOT NULL,
+    seed INTEGER NOT NULL,
+    commit_hash TEXT NOT NULL,
+    exec_time INTEGER NOT NULL,
+    exec_date INTEGER NOT NULL,
+    program TEXT NOT NULL,
+    PRIMARY KEY (run_id, problem_id)
+);
+CREATE INDEX IF NOT EXISTS results_by_problem ON results (problem_id, score);
+";
+
+const COLUMNS: &str =
+    "run_id, problem_id, score, ai, seed, commit_hash, exec_time, exec_date, program";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub run_id: String,
+    pub problem_id: String,
+    pub score: i64,
+    pub ai: String,
+    pub seed: u64,
+    pub commit: String,
+    // 秒
+    pub exec_time: u64,
+    // unix time
+    pub exec_date: u64,
+    pub program: String,
+}
+
+impl Record {
+    fn new(run_id: &str, output: &Output, commit: &str, exec_time: Duration) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Record {
+            run_id: run_id.to_string(),
+            problem_id: output.problem_id.clone(),
+            score: output.score,
+            ai: output.ai.clone(),
+            seed: output.seed,
+            commit: commit.to_string(),
+            exec_time: exec_time.as_secs(),
+            exec_date: now.as_secs(),
+            program: format!("{}", output.program),
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Record {
+            run_id: row.get(0)?,
+            problem_id: row.get(1)?,
+            score: row.get(2)?,
+            ai: row.get(3)?,
+            // SQLite の整数は i64 なので、ビットをそのまま入れている
+            seed: row.get::<_, i64>(4)? as u64,
+            commit: row.get(5)?,
+            exec_time: row.get::<_, i64>(6)? as u64,
+            exec_date: row.get::<_, i64>(7)? as u64,
+            program: row.get(8)?,
+        })
+    }
+}
+
+// run ごとの集計
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub ai: String,
+    pub commit: String,
+    pub n_problems: usize,
+    pub total_score: i64,
+}
+
+// compare の1行。片方の run にしかない問題はもう片方が None
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparedScore {
+    pub problem_id: String,
+    pub a: Option<i64>,
+    pub b: Option<i64>,
+}
+
+pub struct ResultDb {
+    conn: Connection,
+}
+
+impl ResultDb {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open '{}'", path.to_string_lossy()))?;
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(ResultDb { conn })
+    }
+
+    // 同じ run, 問題の結果は上書きする (DynamoDB の PutItem と同じ)
+    pub fn insert(&self, record: &Record) -> anyhow::Result<()> {
+        self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO results ({COLUMNS}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            ),
+            params![
+                record.run_id,
+                record.problem_id,
+                record.score,
+                record.ai,
+                record.seed as i64,
+                record.commit,
+                record.exec_time as i64,
+                record.exec_date as i64,
+                record.program,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // 問題ごとに一番スコアが良い結果。同点なら先に出たもの、同じ秒なら run_id の順
+    pub fn best_per_problem(&self) -> anyhow::Result<Vec<Record>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM results AS r
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM results AS s
+                 WHERE s.problem_id = r.problem_id
+                   AND (s.score, s.exec_date, s.run_id) < (r.score, r.exec_date, r.run_id)
+             )
+             ORDER BY CAST(problem_id AS INTEGER), problem_id"
+        ))?;
+        let records = stmt.query_map([], Record::from_row)?;
+        Ok(records.collect::<Result<_, _>>()?)
+    }
+
+    pub fn run(&self, run_id: &str) -> anyhow::Result<Vec<Record>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM results WHERE run_id = ?
+             ORDER BY CAST(problem_id AS INTEGER), problem_id"
+        ))?;
+        let records = stmt.query_map([run_id], Record::from_row)?;
+        Ok(records.collect::<Result<_, _>>()?)
+    }
+
+    // 新しい run から順に
+    pub fn runs(&self) -> anyhow::Result<Vec<RunSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT run_id, MIN(ai), MIN(commit_hash), COUNT(*), SUM(score)
+             FROM results GROUP BY run_id ORDER BY MIN(exec_date) DESC, run_id",
+        )?;
+        let runs = stmt.query_map([], |row| {
+            Ok(RunSummary {
+                run_id: row.get(0)?,
+                ai: row.get(1)?,
+                commit: row.get(2)?,
+                n_problems: row.get::<_, i64>(3)? as usize,
+                total_score: row.get(4)?,
+            })
+        })?;
+        Ok(runs.collect::<Result<_, _>>()?)
+    }
+
+    // 2つの run の問題ごとのスコア
+    pub fn compare(&self, run_a: &str, run_b: &str) -> anyhow::Result<Vec<ComparedScore>> {
+        let a = self.run(run_a)?;
+        let b = self.run(run_b)?;
+        let mut problem_ids = a
+            .iter()
+            .chain(b.iter())
+            .map(|r| r.problem_id.clone())
+            .collect::<Vec<_>>();
+        problem_ids.sort_by_key(|id| (id.parse::<u32>().ok(), id.clone()));
+        problem_ids.dedup();
+        let score = |records: &[Record], id: &str| {
+            records.iter().find(|r| r.problem_id == id).map(|r| r.score)
+        };
+        Ok(problem_ids
+            .into_iter()
+            .map(|id| ComparedScore {
+                a: score(&a, &id),
+                b: score(&b, &id),
+                problem_id: id,
+            })
+            .collect())
+    }
+}
+
+// run, batch の結果を1つの run として記録する。batch では複数のスレッドから呼ばれる
+pub struct Recorder {
+    db: Mutex<ResultDb>,
+    run_id: String,
+    commit: String,
+}
+
+impl Recorder {
+    // run_id を指定しなければ新しく作る
+    pub fn new(path: &Path, run_id: Option<String>) -> anyhow::Result<Self> {
+        let run_id = run_id.unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+        info!(
+            "record results to {} as run {run_id}",
+            path.to_string_lossy()
+        );
+        Ok(Recorder {
+            db: Mutex::new(ResultDb::open(path)?),
+            run_id,
+            commit: current_commit(),
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn record(&self, output: &Output, exec_time: Duration) -> anyhow::Result<()> {
+        // 既存の解をこの run の ai, seed の結果として記録しない
+        if output.kept {
+            info!(
+                "problem {}: kept the existing solution, not recorded",
+                output.problem_id
+            );
+            return Ok(());
+        }
+        let record = Record::new(&self.run_id, output, &self.commit, exec_time);
+        self.db.lock().unwrap().insert(&record)
+    }
+}
+
+// Lambda と同じく環境変数 COMMIT を使い、なければ手元の git から取る
+fn current_commit() -> String {
+    if let Ok(commit) = env::var("COMMIT") {
+        return commit;
+    }
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DbOpt {
+    #[structopt(long = "db", default_value = "results.sqlite", parse(from_os_str))]
+    path: PathBuf,
+
+    #[structopt(subcommand)]
+    query: DbQuery,
+}
+
+#[derive(Debug, StructOpt)]
+enum DbQuery {
+    #[structopt(about = "Show the best result of each problem")]
+    Best {
+        // 提出用に <output_dir>/<problem_id>.isl を書き出す
+        #[structopt(short = "o", long = "output-dir", parse(from_os_str))]
+        output_dir: Option<PathBuf>,
+    },
+    #[structopt(about = "List runs from the newest")]
+    Runs,
+    #[structopt(about = "Compare scores of two runs problem by problem")]
+    Compare { run_a: String, run_b: String },
+}
+
+pub fn query(opt: DbOpt) -> anyhow::Result<()> {
+    let db = ResultDb::open(&opt.path)?;
+    match opt.query {
+        DbQuery::Best { output_dir } => {
+            let records = db.best_per_problem()?;
+            println!("problem\tscore\trun_id\tcommit\tai");
+            for r in records.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    r.problem_id, r.score, r.run_id, r.commit, r.ai
+                );
+            }
+            let total = records.iter().map(|r| r.score).sum::<i64>();
+            println!("total\t{total}");
+            if let Some(output_dir) = output_dir {
+                fs::create_dir_all(&output_dir)?;
+                for r in records.iter() {
+                    let path = output_dir.join(format!("{}.isl", r.problem_id));
+                    info!("output ISL to: {}", path.to_string_lossy());
+                    fs::write(path, &r.program)?;
+                }
+            }
+        }
+        DbQuery::Runs => {
+            println!("run_id\tproblems\ttotal\tcommit\tai");
+            for run in db.runs()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    run.run_id, run.n_problems, run.total_score, run.commit, run.ai
+                );
+            }
+        }
+        DbQuery::Compare { run_a, run_b } => {
+            let rows = db.compare(&run_a, &run_b)?;
+            if rows.is_empty() {
+                anyhow::bail!("no results for '{run_a}' and '{run_b}'");
+            }
+            let show = |score: Option<i64>| score.map_or("-".to_string(), |s| s.to_string());
+            println!("problem\t{run_a}\t{run_b}\tdiff");
+            // 両方にある問題だけで比べる
+            let (mut total_a, mut total_b) = (0, 0);
+            for ComparedScore { problem_id, a, b } in rows {
+                let diff = match (a, b) {
+                    (Some(a), Some(b)) => {
+                        total_a += a;
+                        total_b += b;
+                        format!("{:+}", b - a)
+                    }
+                    _ => "-".to_string(),
+                };
+                println!("{problem_id}\t{}\t{}\t{diff}", show(a), show(b));
+            }
+            println!("total\t{total_a}\t{total_b}\t{:+}", total_b - total_a);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(run_id: &str, problem_id: &str, score: i64, exec_date: u64) -> Record {
+        Record {
+            run_id: run_id.to_string(),
+            problem_id: problem_id.to_string(),
+            score,
+            ai: "DP|Refine".to_string(),
+            seed: u64::MAX,
+            commit: "abc1234".to_string(),
+            exec_time: 10,
+            exec_date,
+            program: format!("# {run_id}\n"),
+        }
+    }
+
+    #[test]
+    fn test_result_db() {
+        let db = ResultDb::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        db.insert(&record("a", "2", 300, 1)).unwrap();
+        db.insert(&record("a", "10", 100, 1)).unwrap();
+        db.insert(&record("c", "11", 50, 2)).unwrap();
+        db.insert(&record("b", "2", 200, 2)).unwrap();
+        db.insert(&record("b", "10", 100, 2)).unwrap();
+        db.insert(&record("b", "11", 50, 2)).unwrap();
+        // 上書き
+        db.insert(&record("a", "2", 250, 1)).unwrap();
+
+        assert_eq!(record("a", "2", 250, 1), db.run("a").unwrap()[0]);
+
+        // 同点なら先に出た方、同じ秒なら run_id の順
+        assert_eq!(
+            vec![
+                record("b", "2", 200, 2),
+                record("a", "10", 100, 1),
+                record("b", "11", 50, 2)
+            ],
+            db.best_per_problem().unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                (Some(250), Some(200)),
+                (Some(100), Some(100)),
+                (None, Some(50))
+            ],
+            db.compare("a", "b")
+                .unwrap()
+                .iter()
+                .map(|c| (c.a, c.b))
+                .collect::<Vec<_>>()
+        );
+
+        let runs = db.runs().unwrap();
+        assert_eq!(
+            vec!["b", "c", "a"],
+            runs.iter().map(|r| &r.run_id).collect::<Vec<_>>()
+        );
+        assert_eq!((3, 350), (runs[0].n_problems, runs[0].total_score));
+    }
+}
@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::ops::{Add, Sub};
 use std::path::Path;
 
 use crate::isl::{self, Color};
@@ -7,97 +9,170 @@ use glam::IVec4;
 use image::GenericImageView;
 use image::{self, RgbaImage};
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Image(pub Vec<Vec<isl::Color>>);
+pub type Rgba = [u8; 4];
+
+// 画像は1本の連続したバッファに RGBA の u8 で持つ。
+// y は下から数える (ISL の座標と同じ)。pixels[y * width + x]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgba>,
+}
 impl Image {
     pub fn new(w: usize, h: usize) -> Self {
-        Image(vec![vec![glam::Vec4::ONE; w as usize]; h as usize])
+        Image {
+            width: w,
+            height: h,
+            pixels: vec![[255; 4]; w * h],
+        }
     }
     pub fn width(&self) -> usize {
-        self.0[0].len()
+        self.width
     }
     pub fn height(&self) -> usize {
-        self.0.len()
+        self.height
     }
     pub fn area(&self) -> usize {
         self.width() * self.height()
     }
+    pub fn rgba(&self, x: usize, y: usize) -> Rgba {
+        self.pixels[y * self.width + x]
+    }
+    pub fn set_rgba(&mut self, x: usize, y: usize, c: Rgba) {
+        self.pixels[y * self.width + x] = c;
+    }
+    // 0.0 ~ 1.0 の色として読み書きする。書くときは ISL と同じく丸める
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        from_rgba(self.rgba(x, y))
+    }
+    #[allow(dead_code)]
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.set_rgba(x, y, to_rgba(color));
+    }
+    pub fn pixels(&self) -> &[Rgba] {
+        &self.pixels
+    }
+    pub fn row(&self, y: usize) -> &[Rgba] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+    pub fn row_mut(&mut self, y: usize) -> &mut [Rgba] {
+        &mut self.pixels[y * self.width..(y + 1) * self.width]
+    }
+    // [p, p + size) の各行
+    pub fn rect_rows(
+        &self,
+        p: isl::Point,
+        size: isl::Point,
+    ) -> impl DoubleEndedIterator<Item = &[Rgba]> + ExactSizeIterator {
+        let l = p.x as usize;
+        let r = (p.x + size.x) as usize;
+        (p.y as usize..(p.y + size.y) as usize).map(move |y| &self.row(y)[l..r])
+    }
+    pub fn fill_rect(&mut self, p: isl::Point, size: isl::Point, color: Color) {
+        let c = to_rgba(color);
+        let l = p.x as usize;
+        let r = (p.x + size.x) as usize;
+        for y in p.y as usize..(p.y + size.y) as usize {
+            self.row_mut(y)[l..r].fill(c);
+        }
+    }
+    // src の [src_p, src_p + size) を self の dst_p にコピーする
+    pub fn copy_rect(
+        &mut self,
+        src: &Image,
+        src_p: isl::Point,
+        dst_p: isl::Point,
+        size: isl::Point,
+    ) {
+        let w = size.x as usize;
+        for dy in 0..size.y {
+            let sx = src_p.x as usize;
+            let dx = dst_p.x as usize;
+            let src_row = &src.row((src_p.y + dy) as usize)[sx..sx + w];
+            self.row_mut((dst_p.y + dy) as usize)[dx..dx + w].copy_from_slice(src_row);
+        }
+    }
     pub fn average(&self, p: isl::Point, size: isl::Point) -> Color {
-        let mut sum = Color::ZERO;
-        for y in p.y..(p.y + size.y) {
-            for x in p.x..(p.x + size.x) {
-                sum += self.0[y as usize][x as usize];
+        let mut sum = IVec4::ZERO;
+        for row in self.rect_rows(p, size) {
+            for &c in row {
+                sum += IVec4::from_array(c.map(|v| v as i32));
             }
         }
-        return sum / (size.y * size.x) as f32;
+        return sum.as_vec4() / 255.0 / (size.y * size.x) as f32;
     }
     #[allow(dead_code)]
     pub fn majority(&self, p: isl::Point, size: isl::Point) -> Color {
-        // TODO: 高速化（release buildだとまだ早い）
-        let mut all_pixels = vec![];
-        for y in p.y..(p.y + size.y) {
-            for x in p.x..(p.x + size.x) {
-                all_pixels.push(self.0[y as usize][x as usize])
+        // 同数なら先に出てきた色
+        let mut counts = HashMap::<Rgba, usize>::new();
+        for row in self.rect_rows(p, size) {
+            for &c in row {
+                *counts.entry(c).or_insert(0) += 1;
             }
         }
         let mut max_occurrence = 0;
-        let mut most_occurred_color: Color = Color::ZERO;
-        for i in 0..all_pixels.len() {
-            let mut count = 0;
-            let pix = all_pixels[i];
-            for j in i..all_pixels.len() {
-                if pix == all_pixels[j] {
-                    count += 1;
+        let mut most_occurred_color = [0; 4];
+        for row in self.rect_rows(p, size) {
+            for &c in row {
+                let count = counts[&c];
+                if count > max_occurrence {
+                    max_occurrence = count;
+                    most_occurred_color = c;
                 }
             }
-            if count > max_occurrence {
-                max_occurrence = count;
-                most_occurred_color = pix.clone();
-            }
         }
-        return most_occurred_color;
+        return from_rgba(most_occurred_color);
+    }
+    // 長方形内のピクセルとのユークリッド距離の和が最小になる色 (幾何中央値)
+    #[allow(dead_code)]
+    pub fn geometric_median(&self, p: isl::Point, size: isl::Point) -> Color {
+        let pixels = self
+            .rect_rows(p, size)
+            .flatten()
+            .map(|&c| from_rgba(c))
+            .collect::<Vec<_>>();
+        geometric_median(&pixels)
     }
     pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
         let mut img = RgbaImage::new(self.width() as u32, self.height() as u32);
         for pixel in img.enumerate_pixels_mut() {
             let x = pixel.0 as usize;
             let y = self.height() - pixel.1 as usize - 1;
-            let r = (self.0[y][x].x * 255.0).round() as u8;
-            let g = (self.0[y][x].y * 255.0).round() as u8;
-            let b = (self.0[y][x].z * 255.0).round() as u8;
-            let a = (self.0[y][x].w * 255.0).round() as u8;
-            *pixel.2 = image::Rgba([r, g, b, a]);
+            *pixel.2 = image::Rgba(self.rgba(x, y));
         }
         img.save(path)?;
         Ok(())
     }
     #[allow(dead_code)]
     pub fn from_string_array(string_array: &[&str]) -> Self {
-        let v = string_array
-            .into_iter()
-            .map(|row| {
-                row.chars()
-                    .map(|c| match c {
-                        '.' => Color::ONE,
-                        '#' => Color::new(0.0, 0.0, 0.0, 1.0),
-                        'r' => Color::new(1.0, 0.0, 0.0, 1.0),
-                        'g' => Color::new(0.0, 1.0, 0.0, 1.0),
-                        'b' => Color::new(0.0, 0.0, 1.0, 1.0),
-                        'z' => Color::ZERO,
-                        _ => Color::new(0.5, 0.5, 0.5, 1.0),
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
-        Image(v)
+        let h = string_array.len();
+        let w = string_array[0].len();
+        let mut image = Image::new(w, h);
+        for (y, row) in string_array.iter().enumerate() {
+            assert_eq!(w, row.len());
+            for (x, c) in row.chars().enumerate() {
+                let c = match c {
+                    '.' => [255, 255, 255, 255],
+                    '#' => [0, 0, 0, 255],
+                    'r' => [255, 0, 0, 255],
+                    'g' => [0, 255, 0, 255],
+                    'b' => [0, 0, 255, 255],
+                    'z' => [0, 0, 0, 0],
+                    _ => [128, 128, 128, 255],
+                };
+                image.set_rgba(x, y, c);
+            }
+        }
+        image
     }
 }
 impl Display for Image {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = String::new();
-        for row in self.0.iter() {
-            for pixel in row.iter() {
-                let c = (*pixel * 255.0).round().as_ivec4();
+        for y in 0..self.height() {
+            for &pixel in self.row(y) {
+                let c = IVec4::from_array(pixel.map(|v| v as i32));
                 if c == IVec4::new(255, 255, 255, 255) {
                     buf.push('.')
                 } else if c == IVec4::new(0, 0, 0, 255) {
@@ -129,16 +204,190 @@ pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Image> {
     for pixel in img.pixels() {
         let x = pixel.0 as usize;
         let y = (h - pixel.1 - 1) as usize;
-        let [r, g, b, a] = pixel.2 .0;
-        result.0[y][x].x = (r as f32) / 255.0;
-        result.0[y][x].y = (g as f32) / 255.0;
-        result.0[y][x].z = (b as f32) / 255.0;
-        result.0[y][x].w = (a as f32) / 255.0;
+        result.set_rgba(x, y, pixel.2 .0);
     }
 
     Ok(result)
 }
 
+// 長方形内の和を O(1) で求めるための累積和。
+// origin から w × h の範囲だけを持つ (座標は画像全体のもの)
+#[derive(Debug, Clone)]
+pub struct PrefixSum<T> {
+    origin: isl::Point,
+    w: usize,
+    h: usize,
+    // table[y * (w + 1) + x] = origin + ([0, x) × [0, y)) の和
+    table: Vec<T>,
+}
+impl<T> PrefixSum<T>
+where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T>,
+{
+    pub fn new(origin: isl::Point, size: isl::Point, f: impl Fn(usize, usize) -> T) -> Self {
+        let w = size.x as usize;
+        let h = size.y as usize;
+        let mut table = vec![T::default(); (w + 1) * (h + 1)];
+        for y in 0..h {
+            let mut row = T::default();
+            for x in 0..w {
+                row = row + f(origin.x as usize + x, origin.y as usize + y);
+                table[(y + 1) * (w + 1) + x + 1] = table[y * (w + 1) + x + 1] + row;
+            }
+        }
+        PrefixSum {
+            origin,
+            w,
+            h,
+            table,
+        }
+    }
+    pub fn sum(&self, p: isl::Point, size: isl::Point) -> T {
+        let p = p - self.origin;
+        assert!(p.x >= 0 && p.y >= 0);
+        let (l, t) = (p.x as usize, p.y as usize);
+        let (r, b) = ((p.x + size.x) as usize, (p.y + size.y) as usize);
+        assert!(r <= self.w && b <= self.h);
+        let w = self.w + 1;
+        self.table[b * w + r] + self.table[t * w + l]
+            - self.table[t * w + r]
+            - self.table[b * w + l]
+    }
+}
+
+// 画像の長方形についての統計を O(1) で求める。
+// palette の色ごとに、その色で塗ったときの各ピクセルの距離の累積和を持っておく
+#[derive(Debug, Clone)]
+pub struct RectStats {
+    color_sum: PrefixSum<glam::DVec4>,
+    palette: Vec<Color>,
+    distance_maps: Vec<PrefixSum<f64>>,
+}
+impl RectStats {
+    pub fn new(image: &Image, palette: &[Color]) -> Self {
+        let size = isl::Point::new(image.width() as i32, image.height() as i32);
+        Self::new_in_rect(image, palette, isl::Point::ZERO, size)
+    }
+    // [p, p + size) の中の長方形についてだけ求められればよいとき
+    pub fn new_in_rect(image: &Image, palette: &[Color], p: isl::Point, size: isl::Point) -> Self {
+        RectStats {
+            color_sum: PrefixSum::new(p, size, |x, y| image.get(x, y).as_dvec4()),
+            palette: palette.to_vec(),
+            distance_maps: palette
+                .iter()
+                .map(|&color| {
+                    let color = to_rgba(color);
+                    PrefixSum::new(p, size, |x, y| pixel_distance(color, image.rgba(x, y)))
+                })
+                .collect(),
+        }
+    }
+    // image.average と同じ
+    pub fn average(&self, p: isl::Point, size: isl::Point) -> Color {
+        (self.color_sum.sum(p, size) / (size.x * size.y) as f64).as_vec4()
+    }
+    #[allow(dead_code)]
+    pub fn palette(&self) -> &[Color] {
+        &self.palette
+    }
+    // palette[color_index] で塗ったときの calc_partial_one_color_similarity
+    pub fn similarity(&self, color_index: usize, p: isl::Point, size: isl::Point) -> i64 {
+        distance_to_similarity(self.distance_maps[color_index].sum(p, size))
+    }
+}
+
+// 2つの画像の [p, p + size) の各ピクセルの距離の累積和。
+// calc_partial_image_similarity を O(1) で求める
+pub fn image_distance_map(a: &Image, b: &Image, p: isl::Point, size: isl::Point) -> PrefixSum<f64> {
+    PrefixSum::new(p, size, |x, y| pixel_distance(a.rgba(x, y), b.rgba(x, y)))
+}
+
+// 範囲外の成分は 0 ~ 255 に丸める
+pub fn to_rgba(c: Color) -> Rgba {
+    (c * 255.0).round().to_array().map(|v| v as u8)
+}
+
+pub fn from_rgba(c: Rgba) -> Color {
+    Color::from_array(c.map(|v| v as f32)) / 255.0
+}
+
+// 2つのピクセルのユークリッド距離 (0 ~ 255 の値で測る)。
+// 公式の checker と同じく、二乗和は整数で求めて f64 で sqrt する
+pub fn pixel_distance(a: Rgba, b: Rgba) -> f64 {
+    let mut sq = 0;
+    for i in 0..4 {
+        let d = a[i] as i32 - b[i] as i32;
+        sq += d * d;
+    }
+    (sq as f64).sqrt()
+}
+
+// pixel_distance の和から similarity を求める (公式の checker は Math.round(diff * 0.005))
+pub fn distance_to_similarity(distance: f64) -> i64 {
+    (distance * 0.005).round() as i64
+}
+
+// pixels との距離の和が最小になる色を Weiszfeld 法で求める
+pub fn geometric_median(pixels: &[Color]) -> Color {
+    assert!(!pixels.is_empty());
+    let pixels = pixels
+        .iter()
+        .map(|c| c.as_dvec4() * 255.0)
+        .collect::<Vec<_>>();
+    let mut median = pixels.iter().sum::<glam::DVec4>() / pixels.len() as f64;
+    for _ in 0..100 {
+        let mut numerator = glam::DVec4::ZERO;
+        let mut denominator = 0.0;
+        for &q in pixels.iter() {
+            // ちょうどピクセルに重なったときに発散しないようにする
+            let w = 1.0 / (q - median).length().max(1e-6);
+            numerator += q * w;
+            denominator += w;
+        }
+        let next = numerator / denominator;
+        let diff = (next - median).length();
+        median = next;
+        if diff < 1e-4 {
+            break;
+        }
+    }
+    (median / 255.0).as_vec4()
+}
+
+// color で塗ったときの pixels との距離の和 (calc_partial_one_color_similarity の 0.005 倍する前)
+pub fn total_distance(pixels: &[Color], color: Color) -> f64 {
+    let color = to_rgba(color);
+    pixels
+        .iter()
+        .map(|&q| pixel_distance(color, to_rgba(q)))
+        .sum()
+}
+
+// ISL に書ける (各成分が整数の) 色のうち total_distance が最小になるもの。
+// 幾何中央値を丸めた色から、1ずつ動かして良くなる限り探索する
+pub fn best_integer_color(pixels: &[Color]) -> Color {
+    let mut best = (geometric_median(pixels) * 255.0).round();
+    let mut best_distance = total_distance(pixels, best / 255.0);
+    loop {
+        let mut updated = false;
+        for i in 0..4 {
+            for d in [-1.0, 1.0] {
+                let mut c = best;
+                c[i] = (c[i] + d).clamp(0.0, 255.0);
+                let distance = total_distance(pixels, c / 255.0);
+                if distance < best_distance {
+                    best = c;
+                    best_distance = distance;
+                    updated = true;
+                }
+            }
+        }
+        if !updated {
+            return best / 255.0;
+        }
+    }
+}
+
 // k-means を用いて画像の代表色を求める
 pub fn k_means_color_sampling(
     image: &Image,
@@ -157,7 +406,7 @@ pub fn k_means_color_sampling(
     let c = {
         let x = rng.gen_range(sx..(sx + w));
         let y = rng.gen_range(sy..(sy + h));
-        image.0[y][x]
+        image.get(x, y)
     };
     initial_samples.push(c);
     let mut iter = 0;
@@ -166,7 +415,7 @@ pub fn k_means_color_sampling(
         let mut nsd = vec![vec![0.0; w]; h];
         for dy in 0..h {
             for dx in 0..w {
-                let pixel = image.0[sy + dy][sx + dx];
+                let pixel = image.get(sx + dx, sy + dy);
                 let mut min_diff = 10000000.0;
                 for &sc in &initial_samples {
                     let diff = (sc - pixel).length_squared();
@@ -189,7 +438,7 @@ pub fn k_means_color_sampling(
             for (dx, sd) in row.into_iter().enumerate() {
                 cumsum += sd / d_sum;
                 if p < cumsum {
-                    initial_samples.push(image.0[sy + dy][sx + dx]);
+                    initial_samples.push(image.get(sx + dx, sy + dy));
                     break 'outer;
                 }
             }
@@ -210,7 +459,7 @@ pub fn k_means_color_sampling(
 
         for dy in 0..h {
             for dx in 0..w {
-                let pixel = image.0[sy + dy][sx + dx];
+                let pixel = image.get(sx + dx, sy + dy);
                 let mut min_diff = 10000000.0;
                 let mut best_cluster = 0;
                 for (i_cluster, &color) in samples.iter().enumerate() {
@@ -243,18 +492,114 @@ pub fn k_means_color_sampling(
 // image の各ピクセルを samples の中で一番近い色に破壊的に置き換える
 #[allow(dead_code)]
 pub fn replace_pixels_to_nearest_samples(image: &mut Image, samples: &[Color]) {
-    for row in image.0.iter_mut() {
-        for pixel in row.iter_mut() {
-            let mut min_diff = 10000000.0;
-            let mut best_color = Color::ZERO;
-            for color in samples {
-                let diff = (*pixel - *color).length_squared();
-                if diff < min_diff {
-                    min_diff = diff;
-                    best_color = *color;
-                }
+    for pixel in image.pixels.iter_mut() {
+        let mut min_diff = 10000000.0;
+        let mut best_color = Color::ZERO;
+        for color in samples {
+            let diff = (from_rgba(*pixel) - *color).length_squared();
+            if diff < min_diff {
+                min_diff = diff;
+                best_color = *color;
+            }
+        }
+        *pixel = to_rgba(best_color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_accessors() {
+        let mut image = Image::from_string_array(&["rgbg.", "rbrb#", "gggrz"]);
+        assert_eq!((5, 3), (image.width(), image.height()));
+        assert_eq!([0, 0, 255, 255], image.rgba(3, 1));
+        assert_eq!(Color::new(0.0, 1.0, 0.0, 1.0), image.get(1, 0));
+
+        let src = image.clone();
+        image.fill_rect(isl::Point::new(1, 0), isl::Point::new(2, 2), Color::ZERO);
+        image.copy_rect(
+            &src,
+            isl::Point::new(3, 1),
+            isl::Point::new(0, 2),
+            isl::Point::new(2, 1),
+        );
+        assert_eq!("rzzg.\nrzzb#\nb#grz\n", image.to_string());
+        let rows = image
+            .rect_rows(isl::Point::new(2, 1), isl::Point::new(3, 2))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![&image.row(1)[2..], &image.row(2)[2..]], rows);
+    }
+
+    #[test]
+    fn test_rect_stats() {
+        let image = Image::from_string_array(&["rgbg.", "rbrb#", "gggrz", "#.rgb"]);
+        let palette = [
+            Color::new(1.0, 0.0, 0.0, 1.0),
+            Color::new(0.2, 0.4, 0.6, 0.8),
+        ];
+        let stats = RectStats::new(&image, &palette);
+        for (p, size) in [
+            ((0, 0), (5, 4)),
+            ((1, 1), (3, 2)),
+            ((4, 3), (1, 1)),
+            ((2, 0), (2, 4)),
+        ] {
+            let p = isl::Point::new(p.0, p.1);
+            let size = isl::Point::new(size.0, size.1);
+            assert!((stats.average(p, size) - image.average(p, size)).length() < 1e-6);
+            let sub_stats =
+                RectStats::new_in_rect(&image, &palette, isl::Point::new(0, 0), p + size);
+            assert_eq!(
+                stats.similarity(1, p, size),
+                sub_stats.similarity(1, p, size)
+            );
+            for (i, &color) in palette.iter().enumerate() {
+                let expected =
+                    crate::simulator::calc_partial_one_color_similarity(p, size, color, &image);
+                assert_eq!(expected, stats.similarity(i, p, size));
             }
-            *pixel = best_color;
+        }
+    }
+
+    #[test]
+    fn test_majority() {
+        let image = Image::from_string_array(&["rgbg", "rbrb", "gggr"]);
+        let color = image.majority(isl::Point::new(0, 0), isl::Point::new(4, 3));
+        assert_eq!(Color::new(0.0, 1.0, 0.0, 1.0), color);
+        // 同数なら先に出てきた色
+        let color = image.majority(isl::Point::new(0, 1), isl::Point::new(4, 1));
+        assert_eq!(Color::new(1.0, 0.0, 0.0, 1.0), color);
+    }
+
+    #[test]
+    fn test_geometric_median() {
+        // 平均は外れ値に引っ張られるが、幾何中央値は引っ張られない
+        let image = Image::from_string_array(&["rrrrb"]);
+        let p = isl::Point::new(0, 0);
+        let size = isl::Point::new(5, 1);
+        let median = image.geometric_median(p, size);
+        assert!((median - Color::new(1.0, 0.0, 0.0, 1.0)).length() < 1e-3);
+        let pixels = (0..5).map(|x| image.get(x, 0)).collect::<Vec<_>>();
+        assert_eq!(Color::new(1.0, 0.0, 0.0, 1.0), best_integer_color(&pixels));
+    }
+
+    #[test]
+    fn test_best_integer_color() {
+        let pixels = [
+            Color::new(0.0, 0.0, 0.0, 1.0),
+            Color::new(0.0, 0.0, 1.0 / 255.0, 1.0),
+            Color::new(0.0, 1.0 / 255.0, 0.0, 1.0),
+            Color::new(1.0 / 255.0, 0.0, 0.0, 1.0),
+        ];
+        let color = best_integer_color(&pixels);
+        let distance = total_distance(&pixels, color);
+        for c in [
+            Color::new(0.0, 0.0, 0.0, 1.0),
+            Color::new(1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0, 1.0),
+        ] {
+            assert!(distance <= total_distance(&pixels, c));
         }
     }
 }
@@ -0,0 +1,270 @@
+use smallvec::SmallVec;
+
+use crate::isl::*;
+use crate::simulator::{
+    move_cost, program_exec_error, simulate, Block, BlockHandle, ProgramExecError, State,
+};
+
+// AI から使うときのチェックポイントの間隔 (手数)
+pub const CHECKPOINT_INTERVAL: usize = 32;
+
+// 1手分の変更を戻すための記録
+struct Undo {
+    // 書き換わった既存のブロックの変更前の値
+    blocks: SmallVec<[(BlockHandle, Block); 2]>,
+    // 変更前の State.blocks, State.roots の長さ (新しく作られたブロックは捨てる)
+    num_blocks: usize,
+    num_roots: usize,
+    // cut, merge で Active なブロックが変わる場合は変更前の State.active
+    active: Option<Vec<BlockHandle>>,
+    cost: i64,
+}
+
+// 1手ずつ適用・取り消しできるシミュレーター。
+// プログラムの途中の状態をチェックポイントとして持っておき、
+// k 手目を変更したときは k 手目 (か、その手前のチェックポイント) から再計算する
+pub struct IncrementalSimulator {
+    w: usize,
+    h: usize,
+    state: State,
+    cost: i64,
+    moves: Vec<Move>,
+    undo_log: Vec<Undo>,
+    checkpoint_interval: usize,
+    // (何手適用した後か, その時の状態, その時のコスト)
+    checkpoints: Vec<(usize, State, i64)>,
+}
+
+impl IncrementalSimulator {
+    pub fn new(initial_state: &State, w: usize, h: usize, checkpoint_interval: usize) -> Self {
+        assert!(checkpoint_interval > 0);
+        IncrementalSimulator {
+            w,
+            h,
+            state: initial_state.clone(),
+            cost: 0,
+            moves: vec![],
+            undo_log: vec![],
+            checkpoint_interval,
+            checkpoints: vec![(0, initial_state.clone(), 0)],
+        }
+    }
+
+    // 適用済みの手数
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.moves.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // 適用済みの手の move cost の合計
+    pub fn cost(&self) -> i64 {
+        self.cost
+    }
+
+    // 1手適用する。失敗した場合は状態を変えない
+    // エラーは simulate_all と同じ型で返す。失敗は AI が無効な手を試したときだけなので大きくてもよい
+    #[allow(clippy::result_large_err)]
+    pub fn apply(&mut self, mv: &Move) -> Result<i64, ProgramExecError> {
+        let line_number = self.moves.len() + 1;
+        let cost = move_cost(&self.state, mv, self.w, self.h)
+            .map_err(|e| program_exec_error(line_number, mv.clone(), e))?;
+        let undo = Undo {
+            blocks: touched_handles(&self.state, mv)
+                .into_iter()
+                .map(|handle| (handle, self.state.blocks[handle as usize].clone()))
+                .collect(),
+            num_blocks: self.state.blocks.len(),
+            num_roots: self.state.roots.len(),
+            active: match mv {
+                Move::PCut { .. } | Move::LCut { .. } | Move::Merge { .. } => {
+                    Some(self.state.active.clone())
+                }
+                Move::Color { .. } | Move::Swap { .. } => None,
+            },
+            cost,
+        };
+        // simulate は失敗した場合は何も書き換えない
+        simulate(&mut self.state, mv)
+            .map_err(|e| program_exec_error(line_number, mv.clone(), e))?;
+        self.cost += cost;
+        self.moves.push(mv.clone());
+        self.undo_log.push(undo);
+        // 前のチェックポイントから checkpoint_interval 手進んだら
+        let last_checkpoint = self.checkpoints.last().unwrap().0;
+        if self.moves.len() - last_checkpoint == self.checkpoint_interval {
+            self.checkpoints
+                .push((self.moves.len(), self.state.clone(), self.cost));
+        }
+        Ok(cost)
+    }
+
+    // 最後の1手を取り消す
+    pub fn revert(&mut self) -> Option<Move> {
+        let mv = self.moves.pop()?;
+        let undo = self.undo_log.pop().unwrap();
+        self.cost -= undo.cost;
+        self.restore(undo);
+        self.drop_checkpoints_after(self.moves.len());
+        Some(mv)
+    }
+
+    // 先頭 n 手を適用した状態まで戻す
+    pub fn rewind(&mut self, n: usize) {
+        assert!(n <= self.moves.len());
+        let &(checkpoint, _, _) = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(k, _, _)| *k <= n)
+            .unwrap();
+        if self.moves.len() - n <= n - checkpoint {
+            // 取り消していく方が速い
+            while self.moves.len() > n {
+                self.revert();
+            }
+            return;
+        }
+        self.drop_checkpoints_after(checkpoint);
+        let (_, state, cost) = self.checkpoints.last().unwrap();
+        self.state = state.clone();
+        self.cost = *cost;
+        self.undo_log.truncate(checkpoint);
+        let replay = self.moves.split_off(checkpoint);
+        for mv in replay[..(n - checkpoint)].iter() {
+            self.apply(mv).unwrap();
+        }
+    }
+
+    // program を適用した状態にする。すでに適用済みの共通部分は再計算しない
+    #[allow(clippy::result_large_err)]
+    pub fn simulate(&mut self, program: &Program) -> Result<(&State, i64), ProgramExecError> {
+        let common = self
+            .moves
+            .iter()
+            .zip(program.0.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.rewind(common);
+        for mv in program.0[common..].iter() {
+            self.apply(mv)?;
+        }
+        Ok((&self.state, self.cost))
+    }
+
+    fn restore(&mut self, undo: Undo) {
+        self.state.blocks.truncate(undo.num_blocks);
+        self.state.roots.truncate(undo.num_roots);
+        for (handle, block) in undo.blocks {
+            self.state.blocks[handle as usize] = block;
+        }
+        if let Some(active) = undo.active {
+            self.state.active = active;
+        }
+    }
+
+    fn drop_checkpoints_after(&mut self, n: usize) {
+        while self.checkpoints.last().unwrap().0 > n {
+            self.checkpoints.pop();
+        }
+    }
+}
+
+// mv を適用したときに書き換わりうる既存のブロック
+fn touched_handles(state: &State, mv: &Move) -> SmallVec<[BlockHandle; 2]> {
+    match mv {
+        Move::PCut { block_id, .. }
+        | Move::LCut { block_id, .. }
+        | Move::Color { block_id, .. } => state.handle(block_id).into_iter().collect(),
+        Move::Swap { a, b } | Move::Merge { a, b } => {
+            state.handle(a).into_iter().chain(state.handle(b)).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::simulate_all;
+
+    fn program() -> Program {
+        "cut [0] [4, 4]\n\
+         color [0.0] [255, 0, 0, 255]\n\
+         color [0.1] [0, 255, 0, 255]\n\
+         cut [0.2] [X] [6]\n\
+         color [0.2.1] [0, 255, 0, 255]\n\
+         merge [0.1] [0.0]\n\
+         color [0.3] [0, 0, 255, 255]\n\
+         cut [1] [Y] [2]\n\
+         swap [1.0] [1.1]\n\
+         color [1.1] [0, 0, 0, 255]\n\
+         merge [0.2.0] [0.2.1]\n"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_and_revert() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let program = program();
+        let mut sim = IncrementalSimulator::new(&initial_state, 8, 8, 3);
+        for k in 0..=program.len() {
+            let (expected_state, expected_cost) =
+                simulate_all(&Program(program.0[..k].to_vec()), &initial_state, 8, 8).unwrap();
+            let (state, cost) = sim.simulate(&Program(program.0[..k].to_vec())).unwrap();
+            assert_eq!(&expected_state, state);
+            assert_eq!(expected_cost, cost);
+        }
+        while sim.revert().is_some() {}
+        assert_eq!(&initial_state, sim.state());
+        assert_eq!(0, sim.cost());
+    }
+
+    #[test]
+    fn test_simulate_modified_program() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let mut program = program();
+        let mut sim = IncrementalSimulator::new(&initial_state, 8, 8, 2);
+        sim.simulate(&program).unwrap();
+
+        for k in [9, 6, 2, 0] {
+            program.0[k] = match program.0[k] {
+                Move::Color { ref block_id, .. } => Move::Color {
+                    block_id: block_id.clone(),
+                    color: Color::ZERO,
+                },
+                Move::PCut { ref block_id, .. } => Move::PCut {
+                    block_id: block_id.clone(),
+                    point: Point::new(3, 4),
+                },
+                ref mv => mv.clone(),
+            };
+            let expected = simulate_all(&program, &initial_state, 8, 8).unwrap();
+            let (state, cost) = sim.simulate(&program).unwrap();
+            assert_eq!(&expected.0, state);
+            assert_eq!(expected.1, cost);
+        }
+    }
+
+    #[test]
+    fn test_invalid_move_keeps_state() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let mut sim = IncrementalSimulator::new(&initial_state, 8, 8, 2);
+        let mut program = program();
+        sim.simulate(&program).unwrap();
+        let before = sim.state().clone();
+        program.0.push("swap [1.0] [0.3]".parse().unwrap());
+        let err = sim.simulate(&program).unwrap_err();
+        assert_eq!(12, err.line_number);
+        assert_eq!(&before, sim.state());
+        assert_eq!(11, sim.len());
+    }
+}
@@ -1,10 +1,10 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs};
+use std::{fs, path::Path, sync::Arc};
 
 use crate::{
-    image::Image,
-    isl::{BlockId, Color, Point, INVALID_COLOR},
-    simulator::{SimpleBlock, State},
+    image::{self, Image},
+    isl::{Color, Point, INVALID_COLOR},
+    simulator::{Block, SimpleBlock, State},
 };
 
 #[allow(non_snake_case)]
@@ -40,15 +40,23 @@ fn load_initial_config(path: &str) -> Option<InitialConfig> {
 
 pub fn load_initial_state(path: &str, image: &Image) -> State {
     if let Some(config) = load_initial_config(path) {
-        let blocks = HashMap::new();
-        let mut state = State {
-            blocks,
-            next_global_id: config.blocks.len() as u16,
-            cost_coeff_version: if config.sourcePngPNG.is_some() { 1 } else { 0 },
-        };
+        // sourcePngPNG は URL なので、同じファイル名のものを initial.json と同じディレクトリから読む
+        let source_image = config.sourcePngPNG.as_ref().map(|url| {
+            let filename = url.rsplit('/').next().unwrap();
+            let source_path = Path::new(path).with_file_name(filename);
+            let source_image = image::open(&source_path).unwrap_or_else(|err| {
+                panic!("failed to load {}: {err}", source_path.to_string_lossy())
+            });
+            assert!(source_image.width() == image.width());
+            assert!(source_image.height() == image.height());
+            Arc::new(source_image)
+        });
+        let cost_coeff_version = if config.sourcePngPNG.is_some() { 1 } else { 0 };
+        let mut state = State::new(cost_coeff_version, source_image);
         assert!(config.width == image.width() as u32);
         assert!(config.height == image.height() as u32);
         for block in config.blocks.iter() {
+            let block_id = &block.blockId;
             let p = Point::new(block.bottomLeft[0], block.bottomLeft[1]);
             let size = Point::new(block.topRight[0], block.topRight[1]) - p;
             let color = block
@@ -56,9 +64,19 @@ pub fn load_initial_state(path: &str, image: &Image) -> State {
                 .as_ref()
                 .map(|c| Color::new(c[0], c[1], c[2], c[3]) / 255.0)
                 .unwrap_or(INVALID_COLOR);
-            let simple_block = SimpleBlock::new(p, size, color);
-            let block_id = vec![block.blockId.parse().expect("blockId is not integer")];
-            state.blocks.insert(BlockId::new(&block_id), simple_block);
+            let block = match block.pngBottomLeftPoint {
+                Some(ref q) => {
+                    assert!(state.source_image.is_some());
+                    let png_bottom_left_point = Point::new(q[0], q[1]);
+                    let sub_block = SimpleBlock::with_png(p, size, png_bottom_left_point);
+                    Block::from_sub_blocks(p, size, vec![sub_block])
+                }
+                None => Block::new(p, size, color),
+            };
+            let block_id: u16 = block_id.parse().expect("blockId is not integer");
+            // blockId は 0 から順に並んでいる前提
+            assert!(block_id == state.next_global_id());
+            state.add_root_block(block);
         }
         return state;
     } else {
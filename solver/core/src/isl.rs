@@ -1,4 +1,4 @@
-use std::{collections::HashSet, fmt::Display};
+use std::{collections::HashSet, fmt::Display, str::FromStr};
 
 use glam::{IVec2, Vec4};
 use smallvec::SmallVec;
@@ -173,6 +173,74 @@ mod tests {
         };
         assert_eq!("merge [0.4.2] [1]", format!("{}", merge));
     }
+
+    #[test]
+    fn parse_move_test() {
+        let program = "cut [0.4.2] [12, 34]\n\
+                       cut [0.4.2] [Y] [3]\n\
+                       cut [0] [x] [10]\n\
+                       color [0.4.2] [255, 255, 128, 255]\n\
+                       swap [0.4.2] [1]\n\
+                       merge [0.4.2] [1]\n";
+        let parsed: Program = program.parse().unwrap();
+        assert_eq!(
+            Move::LCut {
+                block_id: BlockId::new(&[0]),
+                orientation: Orientation::Vertical,
+                line_number: 10,
+            },
+            parsed.0[2]
+        );
+        let expected = program.replace("[x]", "[X]");
+        assert_eq!(expected, format!("{}", parsed));
+    }
+
+    #[test]
+    fn parse_program_roundtrip_test() {
+        let program = Program(vec![
+            Move::PCut {
+                block_id: BlockId::new(&[0]),
+                point: Point::new(37, 233),
+            },
+            Move::Color {
+                block_id: BlockId::new(&[0, 1]),
+                color: Color::new(254.0, 254.0, 254.0, 255.0) / 255.0,
+            },
+            Move::Merge {
+                a: BlockId::new(&[0, 2]),
+                b: BlockId::new(&[0, 3]),
+            },
+        ]);
+        let parsed: Program = format!("{program}").parse().unwrap();
+        assert_eq!(program, parsed);
+    }
+
+    #[test]
+    fn parse_comment_and_blank_line_test() {
+        let program = "# header\n\n  cut [0] [1,2]\n   # indented\ncolor [0.0] [0,0,0,255]\n";
+        let parsed: Program = program.parse().unwrap();
+        assert_eq!(2, parsed.len());
+    }
+
+    #[test]
+    fn parse_error_position_test() {
+        let err = "cut [0] [1, 2]\ncolor [0.1] [1, 2, 300, 4]"
+            .parse::<Program>()
+            .unwrap_err();
+        assert_eq!((2, 20), (err.line, err.column));
+
+        let err = "paint [0] [1, 2, 3, 4]".parse::<Program>().unwrap_err();
+        assert_eq!((1, 1), (err.line, err.column));
+
+        let err = "swap [0] [1] [2]".parse::<Move>().unwrap_err();
+        assert_eq!((1, 14), (err.line, err.column));
+
+        let err = "[0.]".parse::<BlockId>().unwrap_err();
+        assert_eq!((1, 4), (err.line, err.column));
+
+        assert!(parse_color("[1, 2, 3]").is_err());
+        assert_eq!(Color::ONE, parse_color("[255, 255, 255, 255]").unwrap());
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -210,3 +278,206 @@ impl Program {
         self.0 = ret;
     }
 }
+
+// 公式の checker と同じ文法で ISL を読み込む
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("line {line}, column {column}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    line: usize,
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn new(text: &'a str, line: usize) -> Self {
+        Parser { text, line, pos: 0 }
+    }
+    fn error_at(&self, pos: usize, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.text[..pos].chars().count() + 1,
+            message,
+        }
+    }
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(c) => Err(self.error_at(self.pos, format!("expected '{expected}', found '{c}'"))),
+            None => Err(self.error_at(
+                self.pos,
+                format!("expected '{expected}', found end of line"),
+            )),
+        }
+    }
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !f(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        &self.text[start..self.pos]
+    }
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if digits.is_empty() {
+            return Err(self.error_at(start, "expected a number".to_string()));
+        }
+        digits
+            .parse()
+            .map_err(|_| self.error_at(start, format!("number '{digits}' is out of range")))
+    }
+    fn block_id(&mut self) -> Result<BlockId, ParseError> {
+        self.expect('[')?;
+        let mut id = SmallVec::new();
+        loop {
+            id.push(self.number::<u16>()?);
+            self.skip_whitespace();
+            if self.peek() != Some('.') {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.expect(']')?;
+        Ok(BlockId(id))
+    }
+    fn color(&mut self) -> Result<Color, ParseError> {
+        self.expect('[')?;
+        let mut rgba = [0.0; 4];
+        for (i, c) in rgba.iter_mut().enumerate() {
+            if i != 0 {
+                self.expect(',')?;
+            }
+            *c = self.number::<u8>()? as f32;
+        }
+        self.expect(']')?;
+        Ok(Color::from_array(rgba) / 255.0)
+    }
+    fn end(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.pos < self.text.len() {
+            return Err(self.error_at(self.pos, "unexpected trailing characters".to_string()));
+        }
+        Ok(())
+    }
+    fn parse_move(&mut self) -> Result<Move, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let mv = match self.take_while(|c| c.is_ascii_alphabetic()) {
+            "cut" => {
+                let block_id = self.block_id()?;
+                self.expect('[')?;
+                self.skip_whitespace();
+                let orientation = match self.peek() {
+                    Some('x' | 'X') => Some(Orientation::Vertical),
+                    Some('y' | 'Y') => Some(Orientation::Horizontal),
+                    _ => None,
+                };
+                if let Some(orientation) = orientation {
+                    self.pos += 1;
+                    self.expect(']')?;
+                    self.expect('[')?;
+                    let line_number = self.number()?;
+                    self.expect(']')?;
+                    Move::LCut {
+                        block_id,
+                        orientation,
+                        line_number,
+                    }
+                } else {
+                    let x = self.number()?;
+                    self.expect(',')?;
+                    let y = self.number()?;
+                    self.expect(']')?;
+                    Move::PCut {
+                        block_id,
+                        point: Point::new(x, y),
+                    }
+                }
+            }
+            "color" => {
+                let block_id = self.block_id()?;
+                let color = self.color()?;
+                Move::Color { block_id, color }
+            }
+            "swap" => {
+                let a = self.block_id()?;
+                let b = self.block_id()?;
+                Move::Swap { a, b }
+            }
+            "merge" => {
+                let a = self.block_id()?;
+                let b = self.block_id()?;
+                Move::Merge { a, b }
+            }
+            "" => return Err(self.error_at(start, "expected a move".to_string())),
+            name => return Err(self.error_at(start, format!("unknown move '{name}'"))),
+        };
+        self.end()?;
+        Ok(mv)
+    }
+}
+
+impl FromStr for BlockId {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s, 1);
+        let block_id = parser.block_id()?;
+        parser.end()?;
+        Ok(block_id)
+    }
+}
+
+// Color は glam::Vec4 の別名なので FromStr を実装できない
+pub fn parse_color(s: &str) -> Result<Color, ParseError> {
+    let mut parser = Parser::new(s, 1);
+    let color = parser.color()?;
+    parser.end()?;
+    Ok(color)
+}
+
+impl FromStr for Move {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser::new(s, 1).parse_move()
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut moves = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let trimmed = line.trim();
+            // 空行と # から始まる行 (コメント) は読み飛ばす
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            moves.push(Parser::new(line, i + 1).parse_move()?);
+        }
+        Ok(Program(moves))
+    }
+}
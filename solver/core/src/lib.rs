@@ -1,108 +1,282 @@
 mod ai;
+mod batch;
+mod context;
+mod db;
 mod image;
+mod incremental;
 mod initial_config;
 pub mod isl;
+mod optimizer;
+mod pipeline;
+mod similarity;
 mod simulator;
+mod validator;
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use image::Image;
 use isl::Program;
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use simulator::State;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 use crate::ai::{ChainedAI, HeadAI};
+use crate::context::SolveContext;
+use crate::pipeline::Stage;
+
+// 起動時に1回作るだけなので Opt が大きくても気にしない
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+#[structopt(name = "solver", about = "A solver of ICFPC 2022 problems")]
+enum Command {
+    #[structopt(about = "Solve a problem with AIs")]
+    Run(Opt),
+    #[structopt(about = "Solve all problems in a directory in parallel and write a summary")]
+    Batch(batch::BatchOpt),
+    #[structopt(about = "Evaluate an existing ISL file against a problem")]
+    Score(ScoreOpt),
+    #[structopt(about = "Render an existing ISL file to a PNG")]
+    Render(RenderOpt),
+    #[structopt(about = "List all structural problems of an existing ISL file")]
+    Check(CheckOpt),
+    #[structopt(about = "Check that scores of recorded programs match the official ones")]
+    Verify(VerifyOpt),
+    #[structopt(about = "List AIs usable in --ai with their parameters and defaults")]
+    Ais,
+    #[structopt(about = "Query results recorded with --db")]
+    Db(db::DbOpt),
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "solver", about = "A solver of ICFPC 2022 problems")]
 struct Opt {
+    #[structopt(short = "i", long = "input", parse(from_os_str))]
+    input_path: PathBuf,
+
+    // 指定した場合は HeadAI を使わずにこのプログラムから ChainedAI を始める
+    #[structopt(
+        long = "initial-program",
+        parse(from_os_str),
+        help = "start ChainedAIs from this ISL file instead of a HeadAI, e.g. -a 'Refine'"
+    )]
+    initial_program: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    solve: SolveOpt,
+}
+
+// run と batch に共通のオプション
+#[derive(Debug, StructOpt)]
+struct SolveOpt {
     #[structopt(
         short = "a",
         long = "ai",
-        help = "comma separated list of AIs, e.g. 'Cross,Refine'"
+        help = "pipeline of AIs, e.g. 'DP(divide=12,colors=6)|Refine(iters=50000,algo=hill)'. See 'ais' for parameters"
     )]
     ai: String,
 
-    #[structopt(short = "i", long = "input", parse(from_os_str))]
-    input_path: PathBuf,
-
     #[structopt(short = "o", long = "output-dir", parse(from_os_str))]
     output_dir: PathBuf,
 
+    #[structopt(
+        long = "keep-best",
+        help = "write output only if it scores better than the existing <id>.isl"
+    )]
+    keep_best: bool,
+
     // Lambda で同パラメーターで複数の問題に対して並列実行する時、
     // 最初に適当な run-id を採番して、それがここに渡ってくる (妄想)
     #[structopt(short = "r", long = "run-id")]
     run_id: Option<String>,
 
-    #[structopt(long = "refine-iters", default_value = "30000")]
-    refine_iters: usize,
+    // Lambda が DynamoDB に書くのと同じ内容を手元の SQLite に残す
+    #[structopt(
+        long = "db",
+        parse(from_os_str),
+        help = "record results to this SQLite file, e.g. results.sqlite. See 'db --help' for queries"
+    )]
+    db: Option<PathBuf>,
+
+    // 以下は -a の各段のパラメーターのデフォルト値を変える昔のオプション。
+    // -a 'Refine(iters=50000)' のように書いた方が優先される
+    #[structopt(long = "refine-iters")]
+    refine_iters: Option<usize>,
 
-    #[structopt(long = "refine-algorithm", default_value = "annealing")]
-    refine_algorithm: String,
+    #[structopt(long = "refine-algorithm")]
+    refine_algorithm: Option<String>,
 
-    #[structopt(long = "refine-initial-temperature", default_value = "5.0")]
-    refine_initial_temperature: f64,
+    #[structopt(long = "refine-initial-temperature")]
+    refine_initial_temperature: Option<f64>,
 
-    #[structopt(long = "refine-dp-divide-max", default_value = "10")]
-    refine_dp_divide_max: usize,
+    #[structopt(long = "refine-dp-divide-max")]
+    refine_dp_divide_max: Option<usize>,
 
     #[structopt(long = "refine-show-intermediates")]
     refine_show_intermediates: bool,
 
-    #[structopt(long = "annealing-seconds", default_value = "10")]
-    annealing_seconds: u64,
+    #[structopt(long = "annealing-seconds")]
+    annealing_seconds: Option<u64>,
+
+    #[structopt(long = "dp-divide-num")]
+    dp_divide_num: Option<usize>,
 
-    #[structopt(long = "dp-divide-num", default_value = "8")]
-    dp_divide_num: usize,
+    #[structopt(long = "dp-color-num")]
+    dp_color_num: Option<usize>,
+
+    // 省略したときはランダムに決めてログと Output に残す
+    #[structopt(
+        long = "seed",
+        help = "random seed for all AIs, e.g. to replay a lambda run"
+    )]
+    seed: Option<u64>,
 
-    #[structopt(long = "dp-color-num", default_value = "10")]
-    dp_color_num: usize,
+    // 1問ごとのパイプライン全体の締め切り。過ぎたら各 AI はその時点の最良解を返し、残りの段は飛ばす
+    #[structopt(
+        long = "time-limit",
+        parse(try_from_str = parse_time_limit),
+        help = "stop the whole pipeline of a problem after this many seconds and output the best solution so far"
+    )]
+    time_limit: Option<f64>,
 
     #[structopt(short = "q", help = "disable debug log")]
     quiet: bool,
 }
 
+#[derive(Debug, StructOpt)]
+struct ScoreOpt {
+    #[structopt(short = "i", long = "input", parse(from_os_str))]
+    input_path: PathBuf,
+
+    #[structopt(short = "p", long = "program", parse(from_os_str))]
+    program_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct CheckOpt {
+    #[structopt(short = "i", long = "input", parse(from_os_str))]
+    input_path: PathBuf,
+
+    #[structopt(short = "p", long = "program", parse(from_os_str))]
+    program_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyOpt {
+    // <dir>/expected.json に (問題, プログラム, 公式のスコア, スコアの出どころ) を並べておく
+    #[structopt(
+        short = "d",
+        long = "dir",
+        default_value = "verify",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+
+    #[structopt(long = "problems-dir", default_value = "problems", parse(from_os_str))]
+    problems_dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyCase {
+    problem_id: String,
+    // dir からの相対パス
+    program: String,
+    // 公式のサーバーが返したスコア
+    score: i64,
+    // score を記録した submission など。手元で計算したスコアは入れない
+    source: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct RenderOpt {
+    #[structopt(short = "i", long = "input", parse(from_os_str))]
+    input_path: PathBuf,
+
+    #[structopt(short = "p", long = "program", parse(from_os_str))]
+    program_path: PathBuf,
+
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output_path: PathBuf,
+}
+
+// --initial-program があるときは HeadAI は None
+type Pipeline = (Option<Box<dyn HeadAI>>, Vec<Box<dyn ChainedAI>>);
+
+// Duration::from_secs_f64 は負の値, NaN, 無限大で panic するので、引数を読むときに弾く
+fn parse_time_limit(s: &str) -> anyhow::Result<f64> {
+    let seconds = s
+        .parse::<f64>()
+        .map_err(|e| anyhow::anyhow!("'{s}' is not a number: {e}"))?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        bail!("'{s}' is not a finite number of seconds >= 0");
+    }
+    Ok(seconds)
+}
+
+// HeadAI 1つと ChainedAI の列を作る。--initial-program があるときは全部 ChainedAI
 fn parse_ai_string(
-    ai_str: &str,
-    opt: &Opt,
-) -> anyhow::Result<(Box<dyn HeadAI>, Vec<Box<dyn ChainedAI>>)> {
-    let parts = ai_str.split(',').collect::<Vec<_>>();
-    let head_ai: Box<dyn ai::HeadAI> = match parts[0] {
-        "OneColor" => Box::new(ai::OneColorAI {}),
-        "Grid" => Box::new(ai::GridAI { rows: 4, cols: 4 }),
-        "Cross" => Box::new(ai::CrossAI { size: 3 }),
-        "DP" => Box::new(ai::DpAI::new(opt.dp_divide_num, opt.dp_color_num, 20, None)),
-        // "Merge" => Box::new(ai::MergeAI::new()),
-        "ChangeColor" => Box::new(ai::ChangeColorAI {}),
-        "Swap" => Box::new(ai::SwapAI {}),
-        "Rect" => Box::new(ai::RectAI {}),
-        x => bail!("'{x}' is not a HeadAI"),
-    };
+    opt: &SolveOpt,
+    has_initial_program: bool,
+    seed: u64,
+) -> anyhow::Result<Pipeline> {
+    let ai_str = &opt.ai;
+    let stages = pipeline::build_stages(ai_str, &legacy_params(opt), seed)?;
+    let mut head_ai = None;
     let mut chained_ais = vec![];
-    for name in &parts[1..] {
-        let chained_ai: Box<dyn ai::ChainedAI> = match *name {
-            "Refine" => Box::new(ai::RefineAi {
-                n_iters: opt.refine_iters,
-                algorithm: match opt.refine_algorithm.as_str() {
-                    "hill" | "hillclimbing" => ai::OptimizeAlgorithm::HillClimbing,
-                    "annealing" => ai::OptimizeAlgorithm::Annealing,
-                    x => bail!("'{x}' is not OptimizeAlgorithm"),
-                },
-                initial_temperature: opt.refine_initial_temperature,
-                dp_divide_max: opt.refine_dp_divide_max,
-                show_intermediates: opt.refine_show_intermediates,
-            }),
-            "Annealing" => Box::new(ai::AnnealingAI {
-                time_limit: Duration::from_secs(opt.annealing_seconds),
-            }),
-            x => bail!("'{x}' is not a ChainedAI"),
-        };
-        chained_ais.push(chained_ai);
+    for (i, stage) in stages.into_iter().enumerate() {
+        let is_head = i == 0 && !has_initial_program;
+        match (stage, is_head) {
+            (Stage::Head(ai), true) => head_ai = Some(ai),
+            (Stage::Chained(ai), false) => chained_ais.push(ai),
+            (_, true) => bail!("stage 1 of '{ai_str}' is not a HeadAI"),
+            (_, false) => bail!("stage {} of '{ai_str}' is not a ChainedAI", i + 1),
+        }
     }
     Ok((head_ai, chained_ais))
 }
 
+// 昔からあるオプションで指定されたパラメーター
+fn legacy_params(opt: &SolveOpt) -> Vec<(&'static str, &'static str, String)> {
+    let mut params = vec![];
+    let mut push = |ai, key, value: Option<String>| {
+        if let Some(value) = value {
+            params.push((ai, key, value));
+        }
+    };
+    push("DP", "divide", opt.dp_divide_num.map(|v| v.to_string()));
+    push("DP", "colors", opt.dp_color_num.map(|v| v.to_string()));
+    push("Refine", "iters", opt.refine_iters.map(|v| v.to_string()));
+    push("Refine", "algo", opt.refine_algorithm.clone());
+    push(
+        "Refine",
+        "temperature",
+        opt.refine_initial_temperature.map(|v| v.to_string()),
+    );
+    push(
+        "Refine",
+        "dp_divide_max",
+        opt.refine_dp_divide_max.map(|v| v.to_string()),
+    );
+    push(
+        "Refine",
+        "show_intermediates",
+        Some("true".to_string()).filter(|_| opt.refine_show_intermediates),
+    );
+    push(
+        "Annealing",
+        "seconds",
+        opt.annealing_seconds.map(|v| v.to_string()),
+    );
+    params
+}
+
 pub struct Output {
     pub run_id: Option<String>,
     pub problem_id: String,
@@ -110,34 +284,45 @@ pub struct Output {
     pub score: i64,
     pub output_image_filename: String,
     pub ai: String,
+    pub seed: u64,
+    // keep_best で既存の解の方が良かったので残した。
+    // program, score は既存の解のもので、ai, seed (この run のもの) からは再現できない
+    pub kept: bool,
+    initial_state: State,
 }
 
-pub fn run() -> anyhow::Result<Output> {
-    let opt = Opt::from_args();
-
-    // init logger
-    let loglevel = if opt.quiet { "info" } else { "debug" };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(loglevel)).init();
-
-    let (mut head_ai, chained_ais) = parse_ai_string(&opt.ai, &opt)?;
+impl Output {
+    // アップロードする前にプログラムに構造上の誤りがないか確かめる
+    pub fn validate(&self) -> anyhow::Result<()> {
+        ensure_valid(&self.program, &self.initial_state)
+    }
+}
 
-    if !opt.output_dir.is_dir() {
-        bail!("'{}' is not a directory", opt.output_dir.to_string_lossy());
+fn ensure_valid(program: &Program, initial_state: &State) -> anyhow::Result<()> {
+    let errors = validator::validate(program, initial_state);
+    if !errors.is_empty() {
+        let messages = errors
+            .iter()
+            .map(|e| format!("    {e}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        bail!("program has {} problem(s):\n{messages}", errors.len());
     }
+    Ok(())
+}
 
-    let problem_id = opt
-        .input_path
+// problems/N.png とその隣にある N.initial.json を読み込む
+fn load_problem(input_path: &Path) -> anyhow::Result<(String, Image, State)> {
+    let problem_id = input_path
         .file_stem()
         .expect("--input should be a file name.")
         .to_string_lossy()
         .to_string();
 
-    let mut score_history = vec![];
-
-    let img = image::open(opt.input_path.clone())?;
+    let img = image::open(input_path)?;
 
     let initial_state = initial_config::load_initial_state(
-        &opt.input_path
+        &input_path
             .parent()
             .unwrap()
             .join(format!("{}.initial.json", problem_id))
@@ -146,38 +331,391 @@ pub fn run() -> anyhow::Result<Output> {
         &img,
     );
 
-    let mut program = head_ai.solve(&img, &initial_state);
+    Ok((problem_id, img, initial_state))
+}
+
+fn load_program(path: &Path) -> anyhow::Result<Program> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read '{}'", path.to_string_lossy()))?;
+    let program = content
+        .parse()
+        .with_context(|| format!("failed to parse '{}'", path.to_string_lossy()))?;
+    Ok(program)
+}
+
+fn init_logger(quiet: bool) {
+    let loglevel = if quiet { "info" } else { "debug" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(loglevel)).init();
+}
 
-    score_history.push(simulator::calc_score(&program, &img, &initial_state)?);
+pub fn run() -> anyhow::Result<Output> {
+    run_with_opt(Opt::from_args())
+}
 
-    for mut chained_ai in chained_ais {
-        program = chained_ai.solve(&img, &initial_state, &program);
-        score_history.push(simulator::calc_score(&program, &img, &initial_state)?);
+// cli から使うエントリーポイント。Lambda は run() を直接呼ぶ
+pub fn cli() -> anyhow::Result<()> {
+    match Command::from_iter(args_with_default_command(std::env::args_os())) {
+        Command::Run(opt) => {
+            run_with_opt(opt)?;
+        }
+        Command::Batch(opt) => batch::batch(opt)?,
+        Command::Score(opt) => score(opt)?,
+        Command::Render(opt) => render(opt)?,
+        Command::Check(opt) => check(opt)?,
+        Command::Verify(opt) => verify(opt)?,
+        Command::Db(opt) => db::query(opt)?,
+        Command::Ais => {
+            for def in pipeline::REGISTRY {
+                println!("{}", def.usage());
+            }
+        }
     }
+    Ok(())
+}
 
-    info!("Score History:");
-    for (i, score) in score_history.iter().enumerate() {
-        info!("    {i}: {score}")
+// サブコマンドを付けない以前の呼び方 (./solver.sh -a Cross -i ...) は run として扱う
+fn args_with_default_command(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.collect::<Vec<_>>();
+    let first = args.get(1).map(|arg| arg.to_string_lossy().to_string());
+    if let Some(first) = first {
+        if first.starts_with('-') && !["-h", "--help", "-V", "--version"].contains(&first.as_str())
+        {
+            args.insert(1, OsString::from("run"));
+        }
     }
+    args
+}
 
+fn score(opt: ScoreOpt) -> anyhow::Result<()> {
+    let (problem_id, img, initial_state) = load_problem(&opt.input_path)?;
+    let program = load_program(&opt.program_path)?;
+
+    let (state, move_cost) =
+        simulator::simulate_all(&program, &initial_state, img.width(), img.height())
+            .with_context(|| format!("'{}' is invalid", opt.program_path.to_string_lossy()))?;
+    let similarity = simulator::calc_state_similarity(&state, &img);
     let score = simulator::calc_score(&program, &img, &initial_state)?;
-    let state = simulator::simulate_all(&program, &initial_state, img.width(), img.height())?.0;
+
+    println!("problem:    {problem_id}");
+    println!("moves:      {}", program.len());
+    println!("move cost:  {move_cost}");
+    println!("similarity: {similarity}");
+    println!("score:      {score}");
+    Ok(())
+}
+
+fn render(opt: RenderOpt) -> anyhow::Result<()> {
+    let (_, img, initial_state) = load_problem(&opt.input_path)?;
+    let program = load_program(&opt.program_path)?;
+
+    let state = simulator::simulate_all(&program, &initial_state, img.width(), img.height())
+        .with_context(|| format!("'{}' is invalid", opt.program_path.to_string_lossy()))?
+        .0;
     let output_image = simulator::rasterize_state(&state, img.width(), img.height());
+    output_image.save(&opt.output_path)?;
+    println!("output PNG to: {}", opt.output_path.to_string_lossy());
+    Ok(())
+}
+
+fn check(opt: CheckOpt) -> anyhow::Result<()> {
+    let (_, _, initial_state) = load_problem(&opt.input_path)?;
+    let program = load_program(&opt.program_path)?;
+    ensure_valid(&program, &initial_state)
+        .with_context(|| format!("'{}' is invalid", opt.program_path.to_string_lossy()))?;
+    println!("OK");
+    Ok(())
+}
+
+fn verify(opt: VerifyOpt) -> anyhow::Result<()> {
+    let path = opt.dir.join("expected.json");
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read '{}'", path.to_string_lossy()))?;
+    let cases: Vec<VerifyCase> = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse '{}'", path.to_string_lossy()))?;
+    // 1つもなければ何も確かめていないので、OK にしない
+    if cases.is_empty() {
+        bail!(
+            "no official scores in '{}' (batch records them on submission)",
+            path.to_string_lossy()
+        );
+    }
+
+    let mut n_wrong = 0;
+    for case in cases.iter() {
+        let input_path = opt.problems_dir.join(format!("{}.png", case.problem_id));
+        let (_, img, initial_state) = load_problem(&input_path)?;
+        let program = load_program(&opt.dir.join(&case.program))?;
+        let score = simulator::calc_score(&program, &img, &initial_state)
+            .with_context(|| format!("'{}' is invalid", case.program))?;
+        if score == case.score {
+            println!("OK     {}: {score}", case.program);
+        } else {
+            println!(
+                "WRONG  {}: expect {} ({}), but {score}",
+                case.program, case.score, case.source
+            );
+            n_wrong += 1;
+        }
+    }
+    if n_wrong > 0 {
+        bail!("{n_wrong} of {} score(s) are wrong", cases.len());
+    }
+    Ok(())
+}
+
+// ISL と一緒に <output_dir>/<problem_id>.json に書き出す情報
+#[derive(Debug, Serialize)]
+struct OutputInfo<'a> {
+    run_id: Option<&'a str>,
+    problem_id: &'a str,
+    score: i64,
+    ai: &'a str,
+    seed: u64,
+    // Ctrl-C や SIGTERM で途中で止めた解かどうか
+    interrupted: bool,
+}
+
+// <output_dir>/<problem_id>.isl, .png, .json を書き出し、(プログラム, スコア, PNG, 既存の解を残したか) を返す
+// keep_best のときは既存の解より良い場合だけ上書きし、そうでなければ既存の解を返す
+fn write_output(
+    output_dir: &Path,
+    info: &OutputInfo,
+    program: Program,
+    img: &Image,
+    initial_state: &State,
+    keep_best: bool,
+) -> anyhow::Result<(Program, i64, PathBuf, bool)> {
+    let problem_id = info.problem_id;
+    let score = info.score;
+    let output_filename = output_dir.join(format!("{problem_id}.isl"));
+    let output_image_filename = output_dir.join(format!("{problem_id}.png"));
+
+    // 同じディレクトリを使う他の run が、既存の解を読んでから書き終わるまでの間に割り込まないようにする
+    let _lock = OutputLock::acquire(output_dir.join(format!("{problem_id}.lock")))?;
+
+    if keep_best && output_filename.exists() {
+        let existing = load_program(&output_filename).and_then(|existing| {
+            let existing_score = simulator::calc_score(&existing, img, initial_state)?;
+            Ok((existing, existing_score))
+        });
+        match existing {
+            Ok((existing, existing_score)) if existing_score <= score => {
+                info!(
+                    "keep existing solution: {} (existing: {existing_score}, new: {score})",
+                    output_filename.to_string_lossy()
+                );
+                return Ok((existing, existing_score, output_image_filename, true));
+            }
+            Ok((_, existing_score)) => {
+                info!(
+                    "improved: {existing_score} -> {score} ({})",
+                    existing_score - score
+                );
+            }
+            Err(err) => {
+                warn!(
+                    "existing solution is broken, overwrite it: {}: {err:#}",
+                    output_filename.to_string_lossy()
+                );
+            }
+        }
+    }
+
+    let state = simulator::simulate_all(&program, initial_state, img.width(), img.height())?.0;
+    let output_image = simulator::rasterize_state(&state, img.width(), img.height());
+
+    // 途中で止められても書きかけのファイルが残らないように、一時ファイルに書いてから rename する
+    let tmp_filename = output_dir.join(format!("{problem_id}.tmp.isl"));
+    let tmp_image_filename = output_dir.join(format!("{problem_id}.tmp.png"));
+    let tmp_info_filename = output_dir.join(format!("{problem_id}.tmp.json"));
+    fs::write(&tmp_filename, format!("{program}"))?;
+    output_image.save(&tmp_image_filename)?;
+    fs::write(&tmp_info_filename, serde_json::to_string_pretty(info)?)?;
 
-    let output_filename = opt.output_dir.join(problem_id.clone() + ".isl");
     info!("output ISL to: {}", output_filename.to_string_lossy());
-    fs::write(output_filename, format!("{program}"))?;
+    fs::rename(&tmp_filename, &output_filename)?;
 
-    let output_image_filename = opt.output_dir.join(problem_id.clone() + ".png");
     info!("output PNG to: {}", output_image_filename.to_string_lossy());
-    output_image.save(output_image_filename.clone())?;
+    fs::rename(&tmp_image_filename, &output_image_filename)?;
+
+    let info_filename = output_dir.join(format!("{problem_id}.json"));
+    info!("output score to: {}", info_filename.to_string_lossy());
+    fs::rename(&tmp_info_filename, &info_filename)?;
+
+    Ok((program, score, output_image_filename, false))
+}
+
+// create_new で作れたプロセスだけが持てるロックファイル。drop で消す
+struct OutputLock {
+    path: PathBuf,
+}
+
+impl OutputLock {
+    // 書き出しは数秒もかからないので、これより古いロックは2回目のシグナルなどで消されずに残ったもの
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    fn acquire(path: PathBuf) -> anyhow::Result<Self> {
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(OutputLock { path }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    if matches!(age, Some(age) if age > Self::STALE_AFTER) {
+                        warn!("remove stale lock: {}", path.to_string_lossy());
+                        let _ = fs::remove_file(&path);
+                    } else {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to create '{}'", path.to_string_lossy()))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn run_with_opt(opt: Opt) -> anyhow::Result<Output> {
+    init_logger(opt.solve.quiet);
+
+    let seed = opt.solve.seed.unwrap_or_else(rand::random);
+    info!("seed: {seed}");
+
+    // 止められたら今の段を打ち切って、それまでの最良解を書き出す
+    let cancelled = Arc::new(AtomicBool::new(false));
+    context::cancel_on_signal(cancelled.clone())?;
+
+    let recorder = match &opt.solve.db {
+        Some(path) => Some(db::Recorder::new(path, opt.solve.run_id.clone())?),
+        None => None,
+    };
+
+    // -r がなければ Recorder が決めた run id で記録する
+    let run_id = match &recorder {
+        Some(recorder) => Some(recorder.run_id()),
+        None => opt.solve.run_id.as_deref(),
+    };
+
+    let start = Instant::now();
+    let output = solve_problem(
+        &opt.solve,
+        &opt.input_path,
+        opt.initial_program.as_deref(),
+        seed,
+        run_id,
+        cancelled,
+    )?;
+    if let Some(recorder) = &recorder {
+        recorder.record(&output, start.elapsed())?;
+    }
+    Ok(output)
+}
+
+// 1問を解いて output_dir に書き出す
+fn solve_problem(
+    opt: &SolveOpt,
+    input_path: &Path,
+    initial_program: Option<&Path>,
+    seed: u64,
+    run_id: Option<&str>,
+    cancelled: Arc<AtomicBool>,
+) -> anyhow::Result<Output> {
+    let (head_ai, chained_ais) = parse_ai_string(opt, initial_program.is_some(), seed)?;
+
+    if !opt.output_dir.is_dir() {
+        bail!("'{}' is not a directory", opt.output_dir.to_string_lossy());
+    }
+
+    let (problem_id, img, initial_state) = load_problem(input_path)?;
+
+    // 各段の途中で見つかった解も含めて一番良いもの
+    let best = Arc::new(Mutex::new(None));
+    let mut ctx = SolveContext::default()
+        .with_cancel_flag(cancelled)
+        .with_observer({
+            let best = best.clone();
+            move |program: &Program, score| *best.lock().unwrap() = Some((program.clone(), score))
+        });
+    if let Some(time_limit) = opt.time_limit {
+        ctx = ctx.with_time_limit(Duration::from_secs_f64(time_limit));
+    }
+
+    let mut score_history = vec![];
+
+    let mut program = match head_ai {
+        Some(mut head_ai) => head_ai.solve(&img, &initial_state, &ctx),
+        None => {
+            let path = initial_program.unwrap();
+            info!("load initial program from: {}", path.to_string_lossy());
+            load_program(path)?
+        }
+    };
+    let score = simulator::calc_score(&program, &img, &initial_state)?;
+    ctx.report(&program, score);
+    score_history.push(score);
+
+    let n_chained_ais = chained_ais.len();
+    for (i, mut chained_ai) in chained_ais.into_iter().enumerate() {
+        if ctx.should_stop() {
+            warn!("stopped before stage {} of {}", i + 2, n_chained_ais + 1);
+            break;
+        }
+        program = chained_ai.solve(&img, &initial_state, &program, &ctx);
+        let score = simulator::calc_score(&program, &img, &initial_state)?;
+        ctx.report(&program, score);
+        score_history.push(score);
+    }
+
+    info!("Score History:");
+    for (i, score) in score_history.iter().enumerate() {
+        info!("    {i}: {score}")
+    }
+
+    // 最後の段の出力より、途中で報告された解の方が良いこともある
+    let (program, score) = best.lock().unwrap().take().unwrap();
+    if ctx.is_cancelled() {
+        warn!("interrupted, output the best solution so far: {score}");
+    }
+    let info = OutputInfo {
+        run_id,
+        problem_id: &problem_id,
+        score,
+        ai: &opt.ai,
+        seed,
+        interrupted: ctx.is_cancelled(),
+    };
+    let (program, score, output_image_filename, kept) = write_output(
+        &opt.output_dir,
+        &info,
+        program,
+        &img,
+        &initial_state,
+        opt.keep_best,
+    )?;
 
     Ok(Output {
-        run_id: opt.run_id,
+        run_id: run_id.map(|run_id| run_id.to_string()),
         problem_id: problem_id,
         program: program,
         score,
         output_image_filename: output_image_filename.to_string_lossy().to_string(),
-        ai: opt.ai,
+        ai: opt.ai.clone(),
+        seed,
+        kept,
+        initial_state,
     })
 }
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use crate::isl::*;
+use crate::simulator::{move_cost, move_cost_without_state, simulate, BlockHandle, State};
+
+// 出力画像を変えずに、コストが下がる (少なくとも増えない) ように無駄な手を消す。
+// 何も消せなくなるまで繰り返す。不正なプログラムはそのまま返す
+pub fn eliminate_dead_moves(
+    program: &Program,
+    initial_state: &State,
+    w: usize,
+    h: usize,
+) -> Program {
+    let mut program = program.clone();
+    loop {
+        let prev_len = program.len();
+        // 祖先で塗った色と同じ色の color を先に消す (逆だと祖先の方が消える)
+        program = match remove_noop_colors(&program, initial_state) {
+            Some(p) => p,
+            None => return program,
+        };
+        program = remove_hidden_colors(&program, initial_state);
+        program = match collapse_uniform_cuts(&program, initial_state, w, h) {
+            Some(p) => p,
+            None => return program,
+        };
+        if program.len() == prev_len {
+            return program;
+        }
+    }
+}
+
+// ISL に書き出したときに同じ色になるか
+fn same_color(a: Color, b: Color) -> bool {
+    (a * 255.0).round() == (b * 255.0).round()
+}
+
+pub fn is_descendant(id: &BlockId, ancestor: &BlockId) -> bool {
+    id.0.len() > ancestor.0.len() && id.0.starts_with(&ancestor.0)
+}
+
+// 後の color で全て上書きされる color を消す。
+// ブロックの中身は swap しても block id と一緒に動くので、block id ごとに
+// 「これより後で中身が全て塗り替えられるか」を後ろから求める
+fn remove_hidden_colors(program: &Program, initial_state: &State) -> Program {
+    // merge でできるブロックの global id
+    let mut next_global_id = initial_state.next_global_id();
+    let merged_ids = program
+        .0
+        .iter()
+        .map(|mv| match mv {
+            Move::Merge { .. } => {
+                next_global_id += 1;
+                Some(BlockId::new(&[next_global_id - 1]))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut covered = HashSet::<BlockId>::new();
+    let mut ret = vec![];
+    for (mv, merged_id) in program.0.iter().zip(merged_ids.iter()).rev() {
+        match mv {
+            Move::Color { block_id, .. } => {
+                if covered.contains(block_id) {
+                    continue;
+                }
+                covered.insert(block_id.clone());
+            }
+            Move::PCut { block_id, .. } | Move::LCut { block_id, .. } => {
+                let n = if let Move::PCut { .. } = mv { 4 } else { 2 };
+                let all_children_covered = (0..n).all(|i| {
+                    let mut child = block_id.clone();
+                    child.0.push(i);
+                    covered.contains(&child)
+                });
+                if all_children_covered {
+                    covered.insert(block_id.clone());
+                }
+            }
+            Move::Merge { a, b } => {
+                if covered.contains(merged_id.as_ref().unwrap()) {
+                    covered.insert(a.clone());
+                    covered.insert(b.clone());
+                }
+            }
+            Move::Swap { .. } => {}
+        }
+        ret.push(mv.clone());
+    }
+    ret.reverse();
+    Program(ret)
+}
+
+// 既にその色で塗られている単色のブロックへの color を消す
+fn remove_noop_colors(program: &Program, initial_state: &State) -> Option<Program> {
+    let mut state = initial_state.clone();
+    let mut ret = vec![];
+    for mv in program.0.iter() {
+        if let Move::Color { block_id, color } = mv {
+            let block = state.block(block_id)?;
+            if block.is_flat() && same_color(block.color, *color) {
+                continue;
+            }
+        }
+        simulate(&mut state, mv).ok()?;
+        ret.push(mv.clone());
+    }
+    Some(Program(ret))
+}
+
+// cut 後の子孫が最後に全て同じ色になり、swap や merge で使われないなら、
+// cut とその子孫への手をまとめて1つの color に置き換える
+fn collapse_uniform_cuts(
+    program: &Program,
+    initial_state: &State,
+    w: usize,
+    h: usize,
+) -> Option<Program> {
+    let mut state = initial_state.clone();
+    let mut costs = vec![];
+    // (何手目か, cut するブロックの handle, cut 直前の色 (単色でなければ None))
+    let mut cuts = vec![];
+    for (i, mv) in program.0.iter().enumerate() {
+        costs.push(move_cost(&state, mv, w, h).ok()?);
+        if let Move::PCut { block_id, .. } | Move::LCut { block_id, .. } = mv {
+            let handle = state.handle(block_id)?;
+            let block = &state.blocks[handle as usize];
+            cuts.push((i, handle, Some(block.color).filter(|_| block.is_flat())));
+        }
+        simulate(&mut state, mv).ok()?;
+    }
+
+    let mut ret = program.0.clone();
+    let mut removed = vec![false; program.len()];
+    for (i, handle, color_before) in cuts {
+        if removed[i] {
+            continue;
+        }
+        let block_id = state.block_id(handle);
+        let block = &state.blocks[handle as usize];
+        let color = match uniform_leaf_color(&state, handle) {
+            Some(color) => color,
+            None => continue,
+        };
+        let descendants = (i + 1..program.len())
+            .filter(|&j| referenced_block_ids(&program.0[j]).any(|id| is_descendant(id, &block_id)))
+            .collect::<Vec<_>>();
+        if descendants
+            .iter()
+            .any(|&j| matches!(program.0[j], Move::Swap { .. } | Move::Merge { .. }))
+        {
+            continue;
+        }
+        let removed_cost = costs[i] + descendants.iter().map(|&j| costs[j]).sum::<i64>();
+        let color_move = Move::Color {
+            block_id: block_id.clone(),
+            color,
+        };
+        let added_cost = match color_before {
+            Some(c) if same_color(c, color) => 0,
+            _ => move_cost_without_state(
+                &color_move,
+                block.area() as usize,
+                w,
+                h,
+                state.cost_coeff_version,
+            ),
+        };
+        if added_cost > removed_cost {
+            continue;
+        }
+        removed[i] = true;
+        for &j in descendants.iter() {
+            removed[j] = true;
+        }
+        if added_cost > 0 {
+            ret[i] = color_move;
+            removed[i] = false;
+        }
+    }
+    Some(Program(
+        ret.into_iter()
+            .zip(removed)
+            .filter(|(_, removed)| !removed)
+            .map(|(mv, _)| mv)
+            .collect(),
+    ))
+}
+
+// handle の子孫の末端のブロックが全て同じ色の単色ブロックならその色
+fn uniform_leaf_color(state: &State, handle: BlockHandle) -> Option<Color> {
+    let mut stack = vec![handle];
+    let mut color = None;
+    while let Some(h) = stack.pop() {
+        let block = &state.blocks[h as usize];
+        if !block.children.is_empty() {
+            stack.extend(block.children.iter());
+            continue;
+        }
+        if !block.state.is_active() || !block.is_flat() {
+            return None;
+        }
+        match color {
+            None => color = Some(block.color),
+            Some(c) if same_color(c, block.color) => {}
+            Some(_) => return None,
+        }
+    }
+    color
+}
+
+fn referenced_block_ids(mv: &Move) -> impl Iterator<Item = &BlockId> {
+    let (a, b) = match mv {
+        Move::PCut { block_id, .. }
+        | Move::LCut { block_id, .. }
+        | Move::Color { block_id, .. } => (block_id, None),
+        Move::Swap { a, b } | Move::Merge { a, b } => (a, Some(b)),
+    };
+    std::iter::once(a).chain(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+    use crate::simulator::{rasterize_state, simulate_all};
+
+    fn check(program: &str, expected: &str) {
+        let initial_state = State::initial_state(8, 8, 0);
+        let program: Program = program.parse().unwrap();
+        let expected: Program = expected.parse().unwrap();
+        let optimized = eliminate_dead_moves(&program, &initial_state, 8, 8);
+        assert_eq!(expected, optimized);
+
+        let render = |program: &Program| -> (Image, i64) {
+            let (state, cost) = simulate_all(program, &initial_state, 8, 8).unwrap();
+            (rasterize_state(&state, 8, 8), cost)
+        };
+        let (image, cost) = render(&program);
+        let (optimized_image, optimized_cost) = render(&optimized);
+        assert_eq!(image, optimized_image);
+        assert!(optimized_cost <= cost);
+    }
+
+    #[test]
+    fn test_remove_color_hidden_by_descendants() {
+        check(
+            "color [0] [255, 0, 0, 255]\n\
+             cut [0] [X] [4]\n\
+             color [0.0] [0, 255, 0, 255]\n\
+             swap [0.0] [0.1]\n\
+             color [0.1] [0, 0, 255, 255]\n",
+            "cut [0] [X] [4]\n\
+             color [0.0] [0, 255, 0, 255]\n\
+             swap [0.0] [0.1]\n\
+             color [0.1] [0, 0, 255, 255]\n",
+        );
+    }
+
+    #[test]
+    fn test_keep_color_visible_through_merge() {
+        let program = "color [0] [255, 0, 0, 255]\n\
+                       cut [0] [X] [4]\n\
+                       color [0.0] [0, 255, 0, 255]\n\
+                       merge [0.0] [0.1]\n\
+                       cut [1] [Y] [4]\n\
+                       color [1.0] [0, 0, 255, 255]\n";
+        check(program, program);
+    }
+
+    #[test]
+    fn test_remove_noop_color() {
+        check(
+            "color [0] [255, 255, 255, 255]\n\
+             cut [0] [X] [4]\n\
+             color [0.1] [0, 0, 0, 255]\n\
+             color [0.1] [0, 0, 0, 255]\n",
+            "cut [0] [X] [4]\n\
+             color [0.1] [0, 0, 0, 255]\n",
+        );
+    }
+
+    #[test]
+    fn test_collapse_uniform_cut() {
+        check(
+            "cut [0] [4, 4]\n\
+             cut [0.0] [X] [2]\n\
+             color [0.0.0] [255, 0, 0, 255]\n\
+             color [0.0.1] [255, 0, 0, 255]\n\
+             color [0.1] [0, 0, 255, 255]\n",
+            "cut [0] [4, 4]\n\
+             color [0.0] [255, 0, 0, 255]\n\
+             color [0.1] [0, 0, 255, 255]\n",
+        );
+        // 子孫に塗り直しがなければ cut ごと消える
+        check(
+            "cut [0] [4, 4]\n\
+             cut [0.2] [Y] [6]\n",
+            "",
+        );
+    }
+}
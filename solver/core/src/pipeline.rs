@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::ai::{self, ChainedAI, HeadAI};
+
+// -a に渡す AI のパイプラインの書き方:
+//   DP(divide=12,colors=6)|Refine(iters=50000,algo=hill)|Refine(iters=10000)
+// 区切りは '|' (括弧の外なら ',' でもよい)。括弧を省略するとパラメーターは全てデフォルト
+
+// パイプラインの1段
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageSpec {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+pub fn parse_spec(spec: &str) -> anyhow::Result<Vec<StageSpec>> {
+    let mut stages = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => bail!("unexpected ')' at {i} in '{spec}'"),
+            ')' => depth -= 1,
+            '|' | ',' if depth == 0 => {
+                stages.push(parse_stage(&spec[start..i])?);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        bail!("unclosed '(' in '{spec}'");
+    }
+    stages.push(parse_stage(&spec[start..])?);
+    Ok(stages)
+}
+
+fn parse_stage(stage: &str) -> anyhow::Result<StageSpec> {
+    let stage = stage.trim();
+    let (name, args) = match stage.find('(') {
+        Some(i) => {
+            let args = stage[i + 1..]
+                .strip_suffix(')')
+                .with_context(|| format!("'{stage}' should end with ')'"))?;
+            (stage[..i].trim(), args)
+        }
+        None => (stage, ""),
+    };
+    if name.is_empty() {
+        bail!("empty AI name in '{stage}'");
+    }
+    let mut params: Vec<(String, String)> = vec![];
+    for arg in args.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        let (key, value) = arg
+            .split_once('=')
+            .with_context(|| format!("'{arg}' in '{stage}' should be key=value"))?;
+        let key = key.trim();
+        if params.iter().any(|(k, _)| k == key) {
+            bail!("'{key}' is given twice in '{stage}'");
+        }
+        params.push((key.to_string(), value.trim().to_string()));
+    }
+    Ok(StageSpec {
+        name: name.to_string(),
+        params,
+    })
+}
+
+pub enum Stage {
+    Head(Box<dyn HeadAI>),
+    Chained(Box<dyn ChainedAI>),
+}
+
+// AI を作るときに渡すパラメーター。宣言したデフォルト値を指定された値で上書きしたもの
+pub struct Params {
+    ai_name: &'static str,
+    values: HashMap<&'static str, String>,
+    pub seed: u64,
+}
+
+impl Params {
+    pub fn get<T>(&self, name: &str) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = &self.values[name];
+        value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid {}({name}={value}): {e}", self.ai_name))
+    }
+
+    // 分割数や色数など、0 だと AI の中で 0 除算や範囲外アクセスになるもの
+    pub fn get_positive(&self, name: &str) -> anyhow::Result<usize> {
+        let value = self.get::<usize>(name)?;
+        if value == 0 {
+            bail!("invalid {}({name}=0): must be >= 1", self.ai_name);
+        }
+        Ok(value)
+    }
+}
+
+pub struct AiDef {
+    pub name: &'static str,
+    // (パラメーター名, デフォルト値)
+    pub params: &'static [(&'static str, &'static str)],
+    build: fn(&Params) -> anyhow::Result<Stage>,
+}
+
+impl AiDef {
+    // DP(divide=8,colors=10,kmeans_iters=20) のような、デフォルト値を全て書いた形
+    pub fn usage(&self) -> String {
+        if self.params.is_empty() {
+            return self.name.to_string();
+        }
+        let params = self
+            .params
+            .iter()
+            .map(|(name, default)| format!("{name}={default}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{}({params})", self.name)
+    }
+}
+
+pub static REGISTRY: &[AiDef] = &[
+    // HeadAI
+    AiDef {
+        name: "OneColor",
+        params: &[],
+        build: |_| Ok(Stage::Head(Box::new(ai::OneColorAI {}))),
+    },
+    AiDef {
+        name: "Grid",
+        params: &[("rows", "4"), ("cols", "4")],
+        build: |p| {
+            Ok(Stage::Head(Box::new(ai::GridAI {
+                rows: p.get_positive("rows")?,
+                cols: p.get_positive("cols")?,
+            })))
+        },
+    },
+    AiDef {
+        name: "Cross",
+        params: &[("size", "3")],
+        build: |p| {
+            Ok(Stage::Head(Box::new(ai::CrossAI {
+                size: p.get_positive("size")?,
+            })))
+        },
+    },
+    AiDef {
+        name: "DP",
+        params: &[("divide", "8"), ("colors", "10"), ("kmeans_iters", "20")],
+        build: |p| {
+            Ok(Stage::Head(Box::new(ai::DpAI::new(
+                p.get_positive("divide")?,
+                p.get_positive("colors")?,
+                p.get_positive("kmeans_iters")?,
+                None,
+                p.seed,
+            ))))
+        },
+    },
+    AiDef {
+        name: "ChangeColor",
+        params: &[],
+        build: |_| Ok(Stage::Head(Box::new(ai::ChangeColorAI {}))),
+    },
+    AiDef {
+        name: "Swap",
+        params: &[],
+        build: |_| Ok(Stage::Head(Box::new(ai::SwapAI {}))),
+    },
+    AiDef {
+        name: "Rect",
+        params: &[],
+        build: |_| Ok(Stage::Head(Box::new(ai::RectAI {}))),
+    },
+    // ChainedAI
+    AiDef {
+        name: "Refine",
+        params: &[
+            ("iters", "30000"),
+            ("algo", "annealing"),
+            ("temperature", "5.0"),
+            ("dp_divide_max", "10"),
+            ("show_intermediates", "false"),
+        ],
+        build: |p| {
+            let algorithm = match p.get::<String>("algo")?.as_str() {
+                "hill" | "hillclimbing" => ai::OptimizeAlgorithm::HillClimbing,
+                "annealing" => ai::OptimizeAlgorithm::Annealing,
+                x => bail!("'{x}' is not OptimizeAlgorithm"),
+            };
+            Ok(Stage::Chained(Box::new(ai::RefineAi {
+                n_iters: p.get("iters")?,
+                algorithm,
+                initial_temperature: p.get("temperature")?,
+                dp_divide_max: p.get("dp_divide_max")?,
+                show_intermediates: p.get("show_intermediates")?,
+                seed: p.seed,
+            })))
+        },
+    },
+    AiDef {
+        name: "Annealing",
+        params: &[("seconds", "10")],
+        build: |p| {
+            Ok(Stage::Chained(Box::new(ai::AnnealingAI {
+                time_limit: Duration::from_secs(p.get("seconds")?),
+                seed: p.seed,
+            })))
+        },
+    },
+    AiDef {
+        name: "DeadMove",
+        params: &[],
+        build: |_| Ok(Stage::Chained(Box::new(ai::DeadMoveAI {}))),
+    },
+    AiDef {
+        name: "Prune",
+        params: &[],
+        build: |_| Ok(Stage::Chained(Box::new(ai::PruneAI {}))),
+    },
+    AiDef {
+        name: "Hoist",
+        params: &[("candidates", "3")],
+        build: |p| {
+            Ok(Stage::Chained(Box::new(ai::HoistAI {
+                n_candidates: p.get("candidates")?,
+            })))
+        },
+    },
+    AiDef {
+        name: "Recolor",
+        params: &[],
+        build: |_| Ok(Stage::Chained(Box::new(ai::RecolorAI {}))),
+    },
+];
+
+// spec の各段の AI を作る。
+// overrides は (AI 名, パラメーター名, 値) で、宣言されたデフォルト値を置き換える
+// (--dp-divide-num のような昔のオプション用。spec に書いた値の方が優先)
+pub fn build_stages(
+    spec: &str,
+    overrides: &[(&str, &str, String)],
+    seed: u64,
+) -> anyhow::Result<Vec<Stage>> {
+    // 段ごとの seed は全体の seed から順に作る
+    let mut seeds = SmallRng::seed_from_u64(seed);
+    let mut stages = vec![];
+    for stage in parse_spec(spec)? {
+        let def = REGISTRY
+            .iter()
+            .find(|def| def.name == stage.name)
+            .with_context(|| format!("unknown AI '{}'", stage.name))?;
+        let mut values: HashMap<&'static str, String> = def
+            .params
+            .iter()
+            .map(|&(k, v)| (k, v.to_string()))
+            .collect();
+        for (ai_name, key, value) in overrides {
+            if *ai_name == def.name {
+                *values
+                    .get_mut(*key)
+                    .expect("override of undeclared parameter") = value.clone();
+            }
+        }
+        for (key, value) in stage.params {
+            match values.get_mut(key.as_str()) {
+                Some(v) => *v = value,
+                None => bail!("'{key}' is not a parameter of {}", def.usage()),
+            }
+        }
+        let params = Params {
+            ai_name: def.name,
+            values,
+            seed: seeds.gen(),
+        };
+        stages.push((def.build)(&params)?);
+    }
+    Ok(stages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(name: &str, params: &[(&str, &str)]) -> StageSpec {
+        StageSpec {
+            name: name.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(
+            vec![
+                stage("DP", &[("divide", "12"), ("colors", "6")]),
+                stage("Refine", &[("iters", "50000"), ("algo", "hill")]),
+                stage("Refine", &[]),
+                stage("DeadMove", &[]),
+            ],
+            parse_spec("DP(divide=12, colors=6) | Refine(iters=50000,algo=hill)|Refine(),DeadMove")
+                .unwrap()
+        );
+        // 昔の書き方
+        assert_eq!(
+            vec![stage("Cross", &[]), stage("Refine", &[])],
+            parse_spec("Cross,Refine").unwrap()
+        );
+        for spec in [
+            "DP(divide=12",
+            "DP)",
+            "DP(divide)",
+            "DP(a=1,a=2)",
+            "DP|",
+            "(a=1)",
+        ] {
+            assert!(parse_spec(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_build_stages() {
+        let stages =
+            build_stages("Grid(rows=2)|Refine(iters=10)|Refine(algo=hill)", &[], 0).unwrap();
+        assert!(matches!(stages[0], Stage::Head(_)));
+        assert!(matches!(stages[1], Stage::Chained(_)));
+        assert!(matches!(stages[2], Stage::Chained(_)));
+
+        assert!(build_stages("Grid(depth=2)", &[], 0).is_err());
+        assert!(build_stages("Grid(rows=x)", &[], 0).is_err());
+        assert!(build_stages("Refine(algo=sa)", &[], 0).is_err());
+        assert!(build_stages("Unknown", &[], 0).is_err());
+        for (spec, message) in [
+            ("Grid(rows=0)", "invalid Grid(rows=0): must be >= 1"),
+            ("Grid(cols=0)", "invalid Grid(cols=0): must be >= 1"),
+            ("Cross(size=0)", "invalid Cross(size=0): must be >= 1"),
+            ("DP(divide=0)", "invalid DP(divide=0): must be >= 1"),
+            ("DP(colors=0)", "invalid DP(colors=0): must be >= 1"),
+            (
+                "DP(kmeans_iters=0)",
+                "invalid DP(kmeans_iters=0): must be >= 1",
+            ),
+        ] {
+            let err = build_stages(spec, &[], 0).err().unwrap();
+            assert_eq!(message, err.to_string(), "{spec}");
+        }
+        let overrides = [("Grid", "rows", "x".to_string())];
+        assert!(build_stages("Grid", &overrides, 0).is_err());
+        assert!(build_stages("Grid(rows=2)", &overrides, 0).is_ok());
+    }
+}
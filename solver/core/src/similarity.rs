@@ -0,0 +1,106 @@
+use rayon::prelude::*;
+
+use crate::image::{distance_to_similarity, pixel_distance, Image, Rgba};
+use crate::isl::Point;
+
+// これより小さい長方形はスレッドを立てる方が遅いので逐次で計算する
+const PARALLEL_MIN_AREA: i32 = 1 << 14;
+// 一度に計算するピクセル数。この単位で自動ベクトル化される
+const LANES: usize = 8;
+
+// calc_partial_image_similarity と同じ値を返す並列版。
+// 公式の checker は f64 を上の行から順に足すので、足す順番を変えると和が少し
+// 変わる。ただしどんな順番で足しても、正確な和との差は n * EPSILON * (和) 以下なので、
+// 逐次の和との差はその2倍以下。0.005 倍したものが四捨五入の境目からそれ以上
+// 離れていれば結果は同じになるので、境目に近いときだけ逐次で計算しなおす
+pub fn image_similarity(p: Point, size: Point, a: &Image, b: &Image) -> i64 {
+    if size.x * size.y < PARALLEL_MIN_AREA {
+        return sequential_image_similarity(p, size, a, b);
+    }
+    let l = p.x as usize;
+    let r = (p.x + size.x) as usize;
+    let distance = (p.y..p.y + size.y)
+        .into_par_iter()
+        .map(|y| row_distance(&a.row(y as usize)[l..r], &b.row(y as usize)[l..r]))
+        .sum::<f64>();
+
+    let n = (size.x * size.y) as f64;
+    let margin = 2.0 * n * f64::EPSILON * distance * 0.005;
+    let x = distance * 0.005;
+    if (x - x.floor() - 0.5).abs() <= margin {
+        return sequential_image_similarity(p, size, a, b);
+    }
+    distance_to_similarity(distance)
+}
+
+// 上の行から順に f64 で足す。公式の checker と同じ計算
+fn sequential_image_similarity(p: Point, size: Point, a: &Image, b: &Image) -> i64 {
+    let mut distance: f64 = 0.0;
+    for (a, b) in a.rect_rows(p, size).zip(b.rect_rows(p, size)).rev() {
+        for (&a, &b) in a.iter().zip(b) {
+            distance += pixel_distance(a, b);
+        }
+    }
+    distance_to_similarity(distance)
+}
+
+// 1行の pixel_distance の和。LANES 個の独立な和に分けて、依存のない計算にする
+fn row_distance(a: &[Rgba], b: &[Rgba]) -> f64 {
+    let mut sums = [0.0; LANES];
+    let mut a_chunks = a.chunks_exact(LANES);
+    let mut b_chunks = b.chunks_exact(LANES);
+    for (a, b) in (&mut a_chunks).zip(&mut b_chunks) {
+        let mut sq = [0i32; LANES];
+        for i in 0..LANES {
+            for c in 0..4 {
+                let d = a[i][c] as i32 - b[i][c] as i32;
+                sq[i] += d * d;
+            }
+        }
+        for i in 0..LANES {
+            sums[i] += (sq[i] as f64).sqrt();
+        }
+    }
+    let mut sum = sums.iter().sum::<f64>();
+    for (&a, &b) in a_chunks.remainder().iter().zip(b_chunks.remainder()) {
+        sum += pixel_distance(a, b);
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_image_similarity() {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+        let (w, h) = (203, 157);
+        let mut a = Image::new(w, h);
+        let mut b = Image::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                a.set_rgba(x, y, rng.gen());
+                // 近い色が多い方が距離の端数が色々出る
+                let mut c = a.rgba(x, y);
+                c[rng.gen_range(0..4)] = rng.gen();
+                b.set_rgba(x, y, c);
+            }
+        }
+        for (p, size) in [
+            ((0, 0), (w, h)),
+            ((3, 5), (200, 150)),
+            ((0, 0), (128, 128)),
+            ((17, 0), (1, 157)),
+            ((7, 9), (5, 3)),
+        ] {
+            let p = Point::new(p.0, p.1);
+            let size = Point::new(size.0 as i32, size.1 as i32);
+            assert_eq!(
+                sequential_image_similarity(p, size, &a, &b),
+                image_similarity(p, size, &a, &b)
+            );
+        }
+    }
+}
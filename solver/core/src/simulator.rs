@@ -1,30 +1,72 @@
-use std::collections::HashMap;
+use std::sync::Arc;
+
+use smallvec::SmallVec;
 
 use crate::image::*;
 use crate::isl::*;
+use crate::similarity;
 
 #[derive(Debug, thiserror::Error)]
-#[error("line {line_number}: {mv} is invalid")]
+#[error("line {line_number}: {mv} is invalid: {error}")]
 pub struct ProgramExecError {
-    line_number: usize,
-    mv: Move,
-    block: Option<SimpleBlock>,
+    pub line_number: usize,
+    pub mv: Move,
+    pub error: SimulateError,
 }
 
-pub fn program_exec_error(line_number: usize, mv: Move, state: &State) -> ProgramExecError {
-    let block = match mv {
-        Move::LCut { ref block_id, .. } => state.blocks.get(block_id).cloned(),
-        Move::PCut { ref block_id, .. } => state.blocks.get(block_id).cloned(),
-        Move::Color { ref block_id, .. } => state.blocks.get(block_id).cloned(),
-        _ => None,
-    };
+pub fn program_exec_error(line_number: usize, mv: Move, error: SimulateError) -> ProgramExecError {
     ProgramExecError {
         line_number,
         mv,
-        block,
+        error,
     }
 }
 
+// 1手を適用できなかった理由。座標はキャンバス上のもの
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SimulateError {
+    #[error("block {0} does not exist")]
+    UnknownBlock(BlockId),
+    #[error("block {block_id} is already {state:?}")]
+    ConsumedBlock {
+        block_id: BlockId,
+        state: BlockState,
+    },
+    #[error("point {point} is not inside block {block_id} (p: {p}, size: {size})")]
+    PointCutOutOfBounds {
+        block_id: BlockId,
+        point: Point,
+        p: Point,
+        size: Point,
+    },
+    #[error(
+        "line {orientation} {line_number} is not inside block {block_id} (p: {p}, size: {size})"
+    )]
+    LineCutOutOfBounds {
+        block_id: BlockId,
+        orientation: Orientation,
+        line_number: i32,
+        p: Point,
+        size: Point,
+    },
+    #[error("block {a} (size: {a_size}) and block {b} (size: {b_size}) have different sizes")]
+    SwapShapeMismatch {
+        a: BlockId,
+        a_size: Point,
+        b: BlockId,
+        b_size: Point,
+    },
+    #[error("block {a} (p: {a_p}, size: {a_size}) and block {b} (p: {b_p}, size: {b_size}) are not adjacent")]
+    MergeNotAdjacent {
+        a: BlockId,
+        a_p: Point,
+        a_size: Point,
+        b: BlockId,
+        b_p: Point,
+        b_size: Point,
+    },
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockState {
     Active,
@@ -37,12 +79,15 @@ impl BlockState {
     }
 }
 
+// 単色の長方形。ComplexBlock を構成する部品にもなる
+// png_bottom_left_point が Some のときは単色ではなく、
+// 元画像 (N.source.png) のその位置から切り出した画像を持つ
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct SimpleBlock {
     pub p: Point,
     pub size: Point,
     pub color: Color,
-    pub state: BlockState,
+    pub png_bottom_left_point: Option<Point>,
 }
 impl SimpleBlock {
     pub fn new(p: Point, size: glam::IVec2, color: Color) -> Self {
@@ -50,20 +95,36 @@ impl SimpleBlock {
             p,
             size,
             color,
-            state: BlockState::Active,
+            png_bottom_left_point: None,
+        }
+    }
+    pub fn with_png(p: Point, size: glam::IVec2, png_bottom_left_point: Point) -> Self {
+        SimpleBlock {
+            p,
+            size,
+            color: INVALID_COLOR,
+            png_bottom_left_point: Some(png_bottom_left_point),
         }
     }
     #[allow(dead_code)]
     pub fn rasterize(&self, image: &mut Image) {
         let w = image.width();
         let h = image.height();
-        self.partial_rasterize(glam::IVec2::ZERO, Point::new(w as i32, h as i32), image);
+        self.partial_rasterize(
+            glam::IVec2::ZERO,
+            Point::new(w as i32, h as i32),
+            None,
+            image,
+        );
     }
-    pub fn partial_rasterize(&self, p: Point, size: Point, image: &mut Image) {
-        if self.state == BlockState::Deleted {
-            return;
-        }
-        if self.color == INVALID_COLOR {
+    pub fn partial_rasterize(
+        &self,
+        p: Point,
+        size: Point,
+        source_image: Option<&Image>,
+        image: &mut Image,
+    ) {
+        if self.png_bottom_left_point.is_none() && self.color == INVALID_COLOR {
             return;
         }
         let w = std::cmp::min((p.x + size.x) as usize, image.width());
@@ -72,9 +133,137 @@ impl SimpleBlock {
         let b = std::cmp::min(h, (self.p.y + self.size.y) as usize);
         let l = std::cmp::max(p.x as usize, self.p.x as usize);
         let r = std::cmp::min(w, (self.p.x + self.size.x) as usize);
-        for y in t..b {
-            for x in l..r {
-                image.0[y][x] = self.color;
+        if t >= b || l >= r {
+            return;
+        }
+        let lt = Point::new(l as i32, t as i32);
+        let size = Point::new(r as i32, b as i32) - lt;
+        if let Some(q) = self.png_bottom_left_point {
+            let source_image = source_image.expect("source image is not loaded");
+            image.copy_rect(source_image, lt + (q - self.p), lt, size);
+            return;
+        }
+        image.fill_rect(lt, size, self.color);
+    }
+    // [p, p + size) との共通部分
+    fn intersection(&self, p: Point, size: Point) -> Option<SimpleBlock> {
+        let lt = self.p.max(p);
+        let rb = (self.p + self.size).min(p + size);
+        if lt.x >= rb.x || lt.y >= rb.y {
+            return None;
+        }
+        Some(SimpleBlock {
+            p: lt,
+            size: rb - lt,
+            color: self.color,
+            png_bottom_left_point: self.png_bottom_left_point.map(|q| q + (lt - self.p)),
+        })
+    }
+}
+
+// キャンバス上のブロック。
+// merge でできたブロック (ComplexBlock) は色の違う SimpleBlock の集まりになる。
+// 元画像の一部を持つブロックも、その部分を表す SimpleBlock 1つからなるものとして扱う
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub p: Point,
+    pub size: Point,
+    // 単色のときの色。単色でなければ INVALID_COLOR
+    pub color: Color,
+    pub state: BlockState,
+    // ブロックを構成する SimpleBlock (キャンバス上の座標)。単色のときは空
+    pub sub_blocks: Vec<SimpleBlock>,
+    // cut で作られたブロックなら cut されたブロック
+    pub parent: Option<BlockHandle>,
+    // cut で作られた子ブロック (BlockId の末尾の番号順)
+    pub children: SmallVec<[BlockHandle; 4]>,
+    // BlockId の末尾の番号 (親がなければ global id)
+    pub local_id: u16,
+}
+impl Block {
+    pub fn new(p: Point, size: glam::IVec2, color: Color) -> Self {
+        Block {
+            p,
+            size,
+            color,
+            state: BlockState::Active,
+            sub_blocks: vec![],
+            parent: None,
+            children: SmallVec::new(),
+            local_id: 0,
+        }
+    }
+    // SimpleBlock の集まりからブロックを作る。全部同じ色なら単色のブロックにする
+    pub fn from_sub_blocks(p: Point, size: glam::IVec2, sub_blocks: Vec<SimpleBlock>) -> Self {
+        assert!(!sub_blocks.is_empty());
+        let color = sub_blocks[0].color;
+        if sub_blocks
+            .iter()
+            .all(|b| b.png_bottom_left_point.is_none() && b.color == color)
+        {
+            return Block::new(p, size, color);
+        }
+        Block {
+            p,
+            size,
+            color: INVALID_COLOR,
+            state: BlockState::Active,
+            sub_blocks,
+            parent: None,
+            children: SmallVec::new(),
+            local_id: 0,
+        }
+    }
+    pub fn is_flat(&self) -> bool {
+        self.sub_blocks.is_empty()
+    }
+    // このブロックを構成する SimpleBlock を列挙する
+    pub fn simple_blocks(&self) -> Vec<SimpleBlock> {
+        if !self.is_flat() {
+            self.sub_blocks.clone()
+        } else {
+            vec![SimpleBlock::new(self.p, self.size, self.color)]
+        }
+    }
+    // [p, p + size) の部分を切り出したブロックを作る
+    fn slice(&self, p: Point, size: Point) -> Block {
+        if self.is_flat() {
+            return Block::new(p, size, self.color);
+        }
+        let sub_blocks = self
+            .sub_blocks
+            .iter()
+            .filter_map(|b| b.intersection(p, size))
+            .collect();
+        Block::from_sub_blocks(p, size, sub_blocks)
+    }
+    fn move_to(&mut self, p: Point) {
+        let d = p - self.p;
+        for b in self.sub_blocks.iter_mut() {
+            b.p += d;
+        }
+        self.p = p;
+    }
+    pub fn partial_rasterize(
+        &self,
+        p: Point,
+        size: Point,
+        source_image: Option<&Image>,
+        image: &mut Image,
+    ) {
+        if !self.state.is_active() {
+            return;
+        }
+        if self.is_flat() {
+            SimpleBlock::new(self.p, self.size, self.color).partial_rasterize(
+                p,
+                size,
+                source_image,
+                image,
+            );
+        } else {
+            for b in self.sub_blocks.iter() {
+                b.partial_rasterize(p, size, source_image, image);
             }
         }
     }
@@ -83,55 +272,141 @@ impl SimpleBlock {
     }
 }
 
+// State.blocks の添字
+pub type BlockHandle = u32;
+
+// ブロックは作られた順に blocks に積んでいき、BlockHandle (添字) で参照する。
+// BlockId との対応は roots (global id) と Block.children を辿って求める
 #[derive(Debug, Clone, PartialEq)]
 pub struct State {
-    pub blocks: HashMap<BlockId, SimpleBlock>,
-    pub next_global_id: u16,
+    // 今までに作られた全てのブロック (Deleted, Merged も含む)
+    pub blocks: Vec<Block>,
+    // Active なブロック
+    pub active: Vec<BlockHandle>,
+    // global id -> BlockHandle
+    pub roots: Vec<BlockHandle>,
     pub cost_coeff_version: u8, // 0 or 1
+    // 元画像から始まる問題 (36〜40) の N.source.png
+    pub source_image: Option<Arc<Image>>,
 }
 impl State {
-    pub fn initial_state(w: i32, h: i32, cost_coeff_version: u8) -> Self {
-        let mut blocks = HashMap::new();
-        blocks.insert(
-            BlockId::new(&vec![0]),
-            SimpleBlock::new(Point::new(0, 0), glam::IVec2::new(w, h), Color::ONE),
-        );
+    // ブロックが1つもない State
+    pub fn new(cost_coeff_version: u8, source_image: Option<Arc<Image>>) -> Self {
         State {
-            blocks,
-            next_global_id: 1,
+            blocks: vec![],
+            active: vec![],
+            roots: vec![],
             cost_coeff_version,
+            source_image,
         }
     }
-    // 指定したブロックが1つだけ入ったStateを返す
-    #[allow(dead_code)]
-    pub fn block_state(&self, block_id: BlockId, cost_coeff_version: u8) -> Self {
-        let mut blocks = HashMap::new();
-        let block = self.blocks[&block_id].clone();
-        blocks.insert(block_id, block);
-        State {
-            blocks,
-            next_global_id: self.next_global_id,
-            cost_coeff_version,
+    pub fn initial_state(w: i32, h: i32, cost_coeff_version: u8) -> Self {
+        let mut state = State::new(cost_coeff_version, None);
+        state.add_root_block(Block::new(
+            Point::new(0, 0),
+            glam::IVec2::new(w, h),
+            Color::ONE,
+        ));
+        state
+    }
+    pub fn next_global_id(&self) -> u16 {
+        self.roots.len() as u16
+    }
+    pub fn handle(&self, block_id: &BlockId) -> Option<BlockHandle> {
+        let (&global_id, rest) = block_id.0.split_first()?;
+        let mut handle = *self.roots.get(global_id as usize)?;
+        for &i in rest {
+            handle = *self.blocks[handle as usize].children.get(i as usize)?;
+        }
+        Some(handle)
+    }
+    pub fn block(&self, block_id: &BlockId) -> Option<&Block> {
+        let handle = self.handle(block_id)?;
+        Some(&self.blocks[handle as usize])
+    }
+    pub fn block_id(&self, handle: BlockHandle) -> BlockId {
+        let mut ids = SmallVec::<[u16; 8]>::new();
+        let mut block = &self.blocks[handle as usize];
+        loop {
+            ids.push(block.local_id);
+            match block.parent {
+                Some(parent) => block = &self.blocks[parent as usize],
+                None => break,
+            }
+        }
+        ids.reverse();
+        BlockId(ids)
+    }
+    pub fn active_blocks(&self) -> impl Iterator<Item = (BlockHandle, &Block)> + '_ {
+        self.active
+            .iter()
+            .map(move |&handle| (handle, &self.blocks[handle as usize]))
+    }
+    // 新しい global id のブロックとして追加する
+    pub fn add_root_block(&mut self, mut block: Block) -> BlockHandle {
+        block.parent = None;
+        block.local_id = self.next_global_id();
+        let handle = self.push_block(block);
+        self.roots.push(handle);
+        handle
+    }
+    // ブロックを作らずに global id を1つ進める (Deleted な空のブロックを置いておく)
+    pub fn reserve_global_id(&mut self) {
+        let mut block = Block::new(Point::ZERO, Point::ZERO, INVALID_COLOR);
+        block.state = BlockState::Deleted;
+        block.local_id = self.next_global_id();
+        self.roots.push(self.blocks.len() as BlockHandle);
+        self.blocks.push(block);
+    }
+    fn push_block(&mut self, block: Block) -> BlockHandle {
+        let handle = self.blocks.len() as BlockHandle;
+        self.blocks.push(block);
+        self.active.push(handle);
+        handle
+    }
+    fn deactivate(&mut self, handle: BlockHandle, state: BlockState) {
+        self.blocks[handle as usize].state = state;
+        let i = self.active.iter().position(|&h| h == handle).unwrap();
+        self.active.swap_remove(i);
+    }
+    // handle のブロックを children に分割する
+    fn cut(&mut self, handle: BlockHandle, children: Vec<Block>) {
+        self.deactivate(handle, BlockState::Deleted);
+        for (i, mut child) in children.into_iter().enumerate() {
+            child.parent = Some(handle);
+            child.local_id = i as u16;
+            let child = self.push_block(child);
+            self.blocks[handle as usize].children.push(child);
         }
     }
     #[allow(dead_code)]
     pub fn sample_active_block(&self, rng: &mut impl rand::Rng) -> BlockId {
-        let blocks = self
-            .blocks
-            .iter()
-            .filter(|(_id, block)| block.state.is_active())
-            .collect::<Vec<_>>();
-        let t = rng.gen_range(0..blocks.len());
-        return blocks[t].0.clone();
+        let t = rng.gen_range(0..self.active.len());
+        self.block_id(self.active[t])
+    }
+    fn existing_handle(&self, block_id: &BlockId) -> Result<BlockHandle, SimulateError> {
+        self.handle(block_id)
+            .ok_or_else(|| SimulateError::UnknownBlock(block_id.clone()))
+    }
+    // block_id が Active なブロックを指していればその handle
+    fn active_handle(&self, block_id: &BlockId) -> Result<BlockHandle, SimulateError> {
+        let handle = self.existing_handle(block_id)?;
+        let state = self.blocks[handle as usize].state;
+        if !state.is_active() {
+            return Err(SimulateError::ConsumedBlock {
+                block_id: block_id.clone(),
+                state,
+            });
+        }
+        Ok(handle)
     }
 }
 
-pub fn merge_block(block1: &SimpleBlock, block2: &SimpleBlock) -> Option<SimpleBlock> {
-    let mut block1 = block1.clone();
-    let mut block2 = block2.clone();
+pub fn merge_block(block1: &Block, block2: &Block) -> Option<Block> {
+    let mut block1 = block1;
+    let mut block2 = block2;
     if block1.p.x > block2.p.x || block1.p.y > block2.p.y {
         std::mem::swap(&mut block1, &mut block2);
-        // std::mem::swap(&mut a, &mut b);
     }
     let next_size = if block1.p.x == block2.p.x {
         if block1.size.x != block2.size.x || block1.p.y + block1.size.y != block2.p.y {
@@ -139,142 +414,165 @@ pub fn merge_block(block1: &SimpleBlock, block2: &SimpleBlock) -> Option<SimpleB
         }
         Point::new(block1.size.x, block1.size.y + block2.size.y)
     } else {
-        if block1.size.y != block2.size.y || block1.p.x + block1.size.x != block2.p.x {
+        if block1.p.y != block2.p.y
+            || block1.size.y != block2.size.y
+            || block1.p.x + block1.size.x != block2.p.x
+        {
             return None;
         }
         Point::new(block1.size.x + block2.size.x, block1.size.y)
     };
-    // TODO Colorが混ざるので ComplexBlock にする
-    let next_block = SimpleBlock::new(block1.p, next_size, INVALID_COLOR);
+    let mut sub_blocks = block1.simple_blocks();
+    sub_blocks.append(&mut block2.simple_blocks());
+    let next_block = Block::from_sub_blocks(block1.p, next_size, sub_blocks);
     return Some(next_block);
 }
 
+// 失敗した場合は state を変更しない
 #[allow(dead_code)]
-#[must_use]
-pub fn simulate(state: &mut State, mv: &Move) -> Option<()> {
+pub fn simulate(state: &mut State, mv: &Move) -> Result<(), SimulateError> {
     match mv {
         Move::PCut {
             ref block_id,
             point,
         } => {
-            let mut simple_block = state.blocks.get(block_id)?.clone();
-            let p = simple_block.p;
+            let handle = state.active_handle(block_id)?;
+            let block = &state.blocks[handle as usize];
+            let p = block.p;
             let offset = *point - p;
             if offset.x <= 0
-                || offset.x >= simple_block.size.x
+                || offset.x >= block.size.x
                 || offset.y <= 0
-                || offset.y >= simple_block.size.y
+                || offset.y >= block.size.y
             {
-                return None;
+                return Err(SimulateError::PointCutOutOfBounds {
+                    block_id: block_id.clone(),
+                    point: *point,
+                    p,
+                    size: block.size,
+                });
             }
             let dx = [0, offset.x, offset.x, 0];
             let dy = [0, 0, offset.y, offset.y];
             let nw = [
                 offset.x,
-                simple_block.size.x - offset.x,
-                simple_block.size.x - offset.x,
+                block.size.x - offset.x,
+                block.size.x - offset.x,
                 offset.x,
             ];
             let nh = [
                 offset.y,
                 offset.y,
-                simple_block.size.y - offset.y,
-                simple_block.size.y - offset.y,
+                block.size.y - offset.y,
+                block.size.y - offset.y,
             ];
-            for i in 0..4 {
-                let nx = p.x + dx[i];
-                let ny = p.y + dy[i];
-                let mut next_id = block_id.clone();
-                next_id.0.push(i as u16);
-                let next_simple_block = SimpleBlock::new(
-                    Point::new(nx, ny),
-                    Point::new(nw[i], nh[i]),
-                    simple_block.color,
-                );
-                state.blocks.insert(next_id, next_simple_block);
-            }
-            simple_block.state = BlockState::Deleted;
-            state.blocks.insert(block_id.clone(), simple_block);
+            let children = (0..4)
+                .map(|i| {
+                    block.slice(
+                        Point::new(p.x + dx[i], p.y + dy[i]),
+                        Point::new(nw[i], nh[i]),
+                    )
+                })
+                .collect::<Vec<_>>();
+            state.cut(handle, children);
         }
         Move::LCut {
             ref block_id,
             orientation,
             line_number,
         } => {
-            let mut simple_block = state.blocks.get(block_id)?.clone();
-            let p = simple_block.p;
+            let handle = state.active_handle(block_id)?;
+            let block = &state.blocks[handle as usize];
+            let p = block.p;
             let offset = match orientation {
                 Orientation::Horizontal => *line_number - p.y,
                 Orientation::Vertical => *line_number - p.x,
             };
             let mut dx = [0, 0];
             let mut dy = [0, 0];
-            let mut nw = [simple_block.size.x, simple_block.size.x];
-            let mut nh = [simple_block.size.y, simple_block.size.y];
+            let mut nw = [block.size.x, block.size.x];
+            let mut nh = [block.size.y, block.size.y];
+            let out_of_bounds = || SimulateError::LineCutOutOfBounds {
+                block_id: block_id.clone(),
+                orientation: *orientation,
+                line_number: *line_number,
+                p,
+                size: block.size,
+            };
             match orientation {
                 Orientation::Horizontal => {
-                    if offset <= 0 || simple_block.size.y <= offset {
-                        return None;
+                    if offset <= 0 || block.size.y <= offset {
+                        return Err(out_of_bounds());
                     }
                     dy = [0, offset];
-                    nh = [offset, simple_block.size.y - offset];
+                    nh = [offset, block.size.y - offset];
                 }
                 Orientation::Vertical => {
-                    if offset <= 0 || simple_block.size.x <= offset {
-                        return None;
+                    if offset <= 0 || block.size.x <= offset {
+                        return Err(out_of_bounds());
                     }
                     dx = [0, offset];
-                    nw = [offset, simple_block.size.x - offset];
+                    nw = [offset, block.size.x - offset];
                 }
             }
-            for i in 0..2 {
-                let nx = p.x + dx[i];
-                let ny = p.y + dy[i];
-                let mut next_id = block_id.clone();
-                next_id.0.push(i as u16);
-                let next_simple_block = SimpleBlock::new(
-                    Point::new(nx, ny),
-                    Point::new(nw[i], nh[i]),
-                    simple_block.color,
-                );
-                state.blocks.insert(next_id, next_simple_block);
-            }
-            simple_block.state = BlockState::Deleted;
-            state.blocks.insert(block_id.clone(), simple_block);
+            let children = (0..2)
+                .map(|i| {
+                    block.slice(
+                        Point::new(p.x + dx[i], p.y + dy[i]),
+                        Point::new(nw[i], nh[i]),
+                    )
+                })
+                .collect::<Vec<_>>();
+            state.cut(handle, children);
         }
         Move::Color {
             ref block_id,
             color,
         } => {
-            let mut simple_block = state.blocks.get_mut(block_id)?;
-            simple_block.color = *color;
+            let handle = state.active_handle(block_id)?;
+            let block = &mut state.blocks[handle as usize];
+            // 塗ると ComplexBlock も単色のブロックになる
+            block.color = *color;
+            block.sub_blocks.clear();
         }
         Move::Swap { ref a, ref b } => {
-            let mut block1 = state.blocks.get(a)?.clone();
-            let mut block2 = state.blocks.get(b)?.clone();
-            if block1.size != block2.size {
-                return None;
+            let handle1 = state.active_handle(a)?;
+            let handle2 = state.active_handle(b)?;
+            let p1 = state.blocks[handle1 as usize].p;
+            let p2 = state.blocks[handle2 as usize].p;
+            let size1 = state.blocks[handle1 as usize].size;
+            let size2 = state.blocks[handle2 as usize].size;
+            if size1 != size2 {
+                return Err(SimulateError::SwapShapeMismatch {
+                    a: a.clone(),
+                    a_size: size1,
+                    b: b.clone(),
+                    b_size: size2,
+                });
             }
-            std::mem::swap(&mut block1.p, &mut block2.p);
-            state.blocks.insert(a.clone(), block1);
-            state.blocks.insert(b.clone(), block2);
+            state.blocks[handle1 as usize].move_to(p2);
+            state.blocks[handle2 as usize].move_to(p1);
         }
         Move::Merge { ref a, ref b } => {
-            let mut block1 = state.blocks.get(a)?.clone();
-            let mut block2 = state.blocks.get(b)?.clone();
-            let next_block = merge_block(&block1, &block2)?;
-            // TODO Colorが混ざるので ComplexBlock にする
-            state
-                .blocks
-                .insert(BlockId::new(&vec![state.next_global_id]), next_block);
-            state.next_global_id += 1;
-            block1.state = BlockState::Merged;
-            block2.state = BlockState::Merged;
-            state.blocks.insert(a.clone(), block1);
-            state.blocks.insert(b.clone(), block2);
-        }
-    }
-    Some(())
+            let handle1 = state.active_handle(a)?;
+            let handle2 = state.active_handle(b)?;
+            let block1 = &state.blocks[handle1 as usize];
+            let block2 = &state.blocks[handle2 as usize];
+            let next_block =
+                merge_block(block1, block2).ok_or_else(|| SimulateError::MergeNotAdjacent {
+                    a: a.clone(),
+                    a_p: block1.p,
+                    a_size: block1.size,
+                    b: b.clone(),
+                    b_p: block2.p,
+                    b_size: block2.size,
+                })?;
+            state.deactivate(handle1, BlockState::Merged);
+            state.deactivate(handle2, BlockState::Merged);
+            state.add_root_block(next_block);
+        }
+    }
+    Ok(())
 }
 
 pub fn simulate_all(
@@ -298,32 +596,38 @@ pub fn simulate_partial(
     for line_number in 0..program.len() {
         let mv = &program[line_number];
         cost += move_cost(state, &mv, w, h)
-            .ok_or_else(|| program_exec_error(line_number + 1, mv.clone(), &state))?;
-        simulate(state, mv)
-            .ok_or_else(|| program_exec_error(line_number + 1, mv.clone(), &state))?;
+            .map_err(|e| program_exec_error(line_number + 1, mv.clone(), e))?;
+        simulate(state, mv).map_err(|e| program_exec_error(line_number + 1, mv.clone(), e))?;
     }
     Ok(cost)
 }
 
-static COST_COEFF_TABLE: [[f32; 5]; 2] = [
+static COST_COEFF_TABLE: [[f64; 5]; 2] = [
     // PCut LCut Color Swap Merge
     [10.0, 7.0, 5.0, 3.0, 1.0],
     [3.0, 2.0, 5.0, 3.0, 1.0],
 ];
 
-pub fn move_cost(state: &State, mv: &Move, w: usize, h: usize) -> Option<i64> {
+pub fn move_cost(state: &State, mv: &Move, w: usize, h: usize) -> Result<i64, SimulateError> {
+    let area = |block_id: &BlockId| {
+        let handle = state.existing_handle(block_id)?;
+        Ok(state.blocks[handle as usize].area())
+    };
     let (i, area) = match mv {
-        Move::PCut { ref block_id, .. } => (0, state.blocks.get(block_id)?.area()),
-        Move::LCut { ref block_id, .. } => (1, state.blocks.get(block_id)?.area()),
-        Move::Color { ref block_id, .. } => (2, state.blocks.get(block_id)?.area()),
-        Move::Swap { ref a, .. } => (3, state.blocks.get(a)?.area()),
-        Move::Merge { ref a, ref b } => (
-            4,
-            state.blocks.get(a)?.area().max(state.blocks.get(b)?.area()),
-        ),
+        Move::PCut { ref block_id, .. } => (0, area(block_id)?),
+        Move::LCut { ref block_id, .. } => (1, area(block_id)?),
+        Move::Color { ref block_id, .. } => (2, area(block_id)?),
+        Move::Swap { ref a, .. } => (3, area(a)?),
+        Move::Merge { ref a, ref b } => (4, area(a)?.max(area(b)?)),
     };
     let base = COST_COEFF_TABLE[state.cost_coeff_version as usize][i];
-    Some((base * (w * h) as f32 / area as f32).round() as i64)
+    Ok(cost_formula(base, w * h, area as usize))
+}
+
+// 公式の checker の Math.round(baseCost * (canvas.size / block.size)) と同じ順番で
+// f64 で計算する。割り切れないときの丸めまで一致させるため、整数の式には直さない
+fn cost_formula(base: f64, canvas_area: usize, block_area: usize) -> i64 {
+    (base * (canvas_area as f64 / block_area as f64)).round() as i64
 }
 
 pub fn move_cost_without_state(
@@ -344,7 +648,7 @@ pub fn move_cost_without_state(
         Move::Merge { .. } => 4,
     };
     let base = COST_COEFF_TABLE[cost_coeff_version as usize][i];
-    (base * (w * h) as f32 / target_area as f32).round() as i64
+    cost_formula(base, w * h, target_area)
 }
 
 #[allow(dead_code)]
@@ -371,14 +675,8 @@ pub fn rasterize_parital_state(
 ) {
     assert!(p.x + size.x <= w as i32);
     assert!(p.y + size.y <= h as i32);
-    // ブロック a とブロック b がマージされてブロック c ができたとする。
-    // c を描画するよりも前に a と b が描画されることを保証するために
-    // ここでソートする
-    let mut blocks = state.blocks.iter().collect::<Vec<_>>();
-    blocks.sort_by(|(id1, _), (id2, _)| id1.cmp(id2));
-
-    for (_, simple_block) in blocks {
-        simple_block.partial_rasterize(p, size, output_image);
+    for (_, block) in state.active_blocks() {
+        block.partial_rasterize(p, size, state.source_image.as_deref(), output_image);
     }
 }
 
@@ -412,15 +710,7 @@ pub fn calc_partial_image_similarity(
     current_image: &Image,
     target_image: &Image,
 ) -> i64 {
-    let mut similarity: f64 = 0.0;
-    for y in p.y..(p.y + size.y) {
-        for x in p.x..(p.x + size.x) {
-            let d =
-                current_image.0[y as usize][x as usize] - target_image.0[y as usize][x as usize];
-            similarity += (d * 255.0).round().length() as f64;
-        }
-    }
-    return (similarity * 0.005).round() as i64;
+    similarity::image_similarity(p, size, current_image, target_image)
 }
 
 // 単色で塗りつぶされている場合のsimilarityを計算する
@@ -430,14 +720,18 @@ pub fn calc_partial_one_color_similarity(
     color: Color,
     target_image: &Image,
 ) -> i64 {
+    // ISL に書き出すと整数に丸められるので、丸めた色で比べる
+    let color = to_rgba(color);
     let mut similarity: f64 = 0.0;
-    for y in p.y..std::cmp::min(p.y + size.y, target_image.height() as i32) {
-        for x in p.x..std::cmp::min(p.x + size.x, target_image.width() as i32) {
-            let d = color - target_image.0[y as usize][x as usize];
-            similarity += (d * 255.0).round().length() as f64;
+    // 画像からはみ出す部分は数えない
+    let canvas = Point::new(target_image.width() as i32, target_image.height() as i32);
+    let size = (p + size).min(canvas) - p;
+    for row in target_image.rect_rows(p, size).rev() {
+        for &pixel in row {
+            similarity += pixel_distance(color, pixel);
         }
     }
-    return (similarity * 0.005).round() as i64;
+    distance_to_similarity(similarity)
 }
 
 #[allow(dead_code)]
@@ -811,6 +1105,166 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_simulate_swap_merged_block() {
+        let mut state = State::initial_state(4, 2, 0);
+        let program: Program = "cut [0] [X] [2]\n\
+                                color [0.0] [255, 0, 0, 255]\n\
+                                cut [0.1] [X] [3]\n\
+                                color [0.1.1] [0, 255, 0, 255]\n\
+                                merge [0.1.0] [0.1.1]\n\
+                                swap [0.0] [1]\n"
+            .parse()
+            .unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+
+        #[rustfmt::skip]
+        let expected = Image::from_string_array(&[
+            ".grr",
+            ".grr",
+        ]);
+
+        let actual = rasterize_state(&state, 4, 2);
+
+        eprint!("actual:\n{}", actual);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_simulate_cut_and_swap_merged_block() {
+        let mut state = State::initial_state(4, 4, 0);
+        let program: Program = "cut [0] [2, 2]\n\
+                                color [0.0] [255, 0, 0, 255]\n\
+                                color [0.1] [0, 255, 0, 255]\n\
+                                color [0.2] [0, 0, 255, 255]\n\
+                                merge [0.0] [0.1]\n\
+                                merge [0.2] [0.3]\n\
+                                merge [1] [2]\n\
+                                cut [3] [X] [1]\n\
+                                cut [3.1] [X] [3]\n\
+                                swap [3.0] [3.1.1]\n"
+            .parse()
+            .unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+
+        #[rustfmt::skip]
+        let expected = Image::from_string_array(&[
+            "grgr",
+            "grgr",
+            "b.b.",
+            "b.b.",
+        ]);
+
+        let actual = rasterize_state(&state, 4, 4);
+
+        eprint!("actual:\n{}", actual);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_simulate_merged_block_is_consumed() {
+        let mut state = State::initial_state(4, 2, 0);
+        let program: Program = "cut [0] [X] [2]\nmerge [0.0] [0.1]\n".parse().unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+        let mv = "color [0.0] [0, 0, 0, 255]".parse().unwrap();
+        assert!(simulate(&mut state, &mv).is_err());
+        let mv = "cut [0] [X] [1]".parse().unwrap();
+        assert!(simulate(&mut state, &mv).is_err());
+    }
+
+    #[test]
+    fn test_simulate_error() {
+        let mut state = State::initial_state(4, 4, 0);
+        let program: Program = "cut [0] [2, 2]\ncut [0.3] [Y] [3]\n".parse().unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+        let check = |mv: &str, expected: SimulateError| {
+            let mut state = state.clone();
+            assert_eq!(Err(expected), simulate(&mut state, &mv.parse().unwrap()));
+        };
+        check(
+            "color [0.4] [0, 0, 0, 255]",
+            SimulateError::UnknownBlock("[0.4]".parse().unwrap()),
+        );
+        check(
+            "color [0.3] [0, 0, 0, 255]",
+            SimulateError::ConsumedBlock {
+                block_id: "[0.3]".parse().unwrap(),
+                state: BlockState::Deleted,
+            },
+        );
+        check(
+            "cut [0.1] [1, 1]",
+            SimulateError::PointCutOutOfBounds {
+                block_id: "[0.1]".parse().unwrap(),
+                point: Point::new(1, 1),
+                p: Point::new(2, 0),
+                size: Point::new(2, 2),
+            },
+        );
+        check(
+            "cut [0.3.0] [X] [2]",
+            SimulateError::LineCutOutOfBounds {
+                block_id: "[0.3.0]".parse().unwrap(),
+                orientation: Orientation::Vertical,
+                line_number: 2,
+                p: Point::new(0, 2),
+                size: Point::new(2, 1),
+            },
+        );
+        check(
+            "swap [0.0] [0.3.0]",
+            SimulateError::SwapShapeMismatch {
+                a: "[0.0]".parse().unwrap(),
+                a_size: Point::new(2, 2),
+                b: "[0.3.0]".parse().unwrap(),
+                b_size: Point::new(2, 1),
+            },
+        );
+        check(
+            "merge [0.0] [0.2]",
+            SimulateError::MergeNotAdjacent {
+                a: "[0.0]".parse().unwrap(),
+                a_p: Point::new(0, 0),
+                a_size: Point::new(2, 2),
+                b: "[0.2]".parse().unwrap(),
+                b_p: Point::new(2, 2),
+                b_size: Point::new(2, 2),
+            },
+        );
+    }
+
+    #[test]
+    fn test_block_handle_and_block_id() {
+        let mut state = State::initial_state(4, 4, 0);
+        let program: Program = "cut [0] [2, 2]\ncut [0.3] [Y] [3]\nmerge [0.0] [0.1]\n"
+            .parse()
+            .unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+        let mut ids = state
+            .active_blocks()
+            .map(|(handle, _)| state.block_id(handle).to_string())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec!["[0.2]", "[0.3.0]", "[0.3.1]", "[1]"], ids);
+        for (handle, _) in state.active_blocks() {
+            assert_eq!(Some(handle), state.handle(&state.block_id(handle)));
+        }
+        let block = state.block(&"[0.3.1]".parse().unwrap()).unwrap();
+        assert_eq!(Point::new(0, 3), block.p);
+        assert!(state.block(&"[0.4]".parse().unwrap()).is_none());
+        assert!(state.block(&"[2]".parse().unwrap()).is_none());
+    }
+
     #[test]
     fn test_simple_block_rasterize() {
         let red = Color::new(1.0, 0.0, 0.0, 1.0);
@@ -893,7 +1347,7 @@ mod tests {
             color: Color::ZERO,
         };
         let actual = move_cost(&state, &mv, 5, 3).unwrap();
-        let expected = (5.0f32 * (5.0 * 3.0) / (3.0 * 3.0)).round() as i64;
+        let expected = (5.0f64 * ((5.0 * 3.0) / (3.0 * 3.0))).round() as i64;
         assert_eq!(expected, actual);
     }
 
@@ -923,4 +1377,35 @@ mod tests {
         let score = calc_score(&Program(program), &image, &state).unwrap();
         assert_eq!(138571, score);
     }
+
+    #[test]
+    fn reproduce_problem_36_source_png() {
+        let image = crate::image::open("../problems/36.png").unwrap();
+        let source_image = crate::image::open("../problems/36.source.png").unwrap();
+        let mut state =
+            crate::initial_config::load_initial_state("../problems/36.initial.json", &image);
+        assert_eq!(source_image, rasterize_state(&state, 400, 400));
+
+        // 元画像の一部はブロックと一緒に動く
+        let program: Program = "cut [0] [X] [100]\n\
+                                cut [0.1] [X] [200]\n\
+                                swap [0.0] [0.1.0]\n\
+                                color [0.1.1] [0, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        for mv in program.0.iter() {
+            simulate(&mut state, mv).unwrap();
+        }
+        let actual = rasterize_state(&state, 400, 400);
+        for y in 0..400 {
+            for x in 0..400 {
+                let expected = match x {
+                    0..=99 => source_image.rgba(x + 100, y),
+                    100..=199 => source_image.rgba(x - 100, y),
+                    _ => [0, 0, 0, 255],
+                };
+                assert_eq!(expected, actual.rgba(x, y));
+            }
+        }
+    }
 }
@@ -0,0 +1,129 @@
+use crate::isl::*;
+use crate::simulator::{move_cost, program_exec_error, simulate, ProgramExecError, State};
+
+// 目標画像を使わずに、プログラムの構造上の誤りを全て列挙する。
+// simulate_all と違って最初の誤りで止まらず、誤った手は飛ばして続ける。
+// 誤った手が作るはずだったブロックを参照する手は、それ自体の誤りとしては報告しない
+pub fn validate(program: &Program, initial_state: &State) -> Vec<ProgramExecError> {
+    let mut state = initial_state.clone();
+    let mut errors = vec![];
+    // 誤った手のせいで存在しないブロック (とその子孫)
+    let mut poisoned: Vec<BlockId> = vec![];
+    for (i, mv) in program.0.iter().enumerate() {
+        let is_poisoned = referenced_block_ids(mv)
+            .iter()
+            .any(|id| poisoned.iter().any(|p| id.0.starts_with(&p.0)));
+        let result = if is_poisoned {
+            None
+        } else {
+            // 面積だけ使うので w, h は何でもよい
+            Some(move_cost(&state, mv, 1, 1).and_then(|_| simulate(&mut state, mv)))
+        };
+        match result {
+            Some(Ok(())) => continue,
+            Some(Err(error)) => errors.push(program_exec_error(i + 1, mv.clone(), error)),
+            None => {}
+        }
+        match mv {
+            Move::PCut { block_id, .. } | Move::LCut { block_id, .. } => {
+                // 既に cut されたブロックをもう一度 cut した場合は、子は前の cut で本当にある
+                let n = if let Move::PCut { .. } = mv { 4 } else { 2 };
+                for k in 0..n {
+                    let mut id = block_id.clone();
+                    id.0.push(k);
+                    if state.handle(&id).is_none() {
+                        poisoned.push(id);
+                    }
+                }
+            }
+            Move::Merge { .. } => {
+                // 後ろの手の global id がずれないように番号だけ使っておく
+                poisoned.push(BlockId::new(&[state.next_global_id()]));
+                state.reserve_global_id();
+            }
+            Move::Color { .. } | Move::Swap { .. } => {}
+        }
+    }
+    errors
+}
+
+fn referenced_block_ids(mv: &Move) -> Vec<&BlockId> {
+    match mv {
+        Move::PCut { block_id, .. }
+        | Move::LCut { block_id, .. }
+        | Move::Color { block_id, .. } => vec![block_id],
+        Move::Swap { a, b } | Move::Merge { a, b } => vec![a, b],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimulateError;
+
+    #[test]
+    fn test_validate_valid_program() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let program: Program = "cut [0] [4, 4]\n\
+                                swap [0.0] [0.2]\n\
+                                merge [0.0] [0.1]\n\
+                                color [1] [255, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        assert!(validate(&program, &initial_state).is_empty());
+    }
+
+    #[test]
+    fn test_validate_reports_all_errors() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let program: Program = "cut [0] [4, 4]\n\
+                                cut [0.0] [X] [6]\n\
+                                color [0.0.1] [0, 0, 0, 255]\n\
+                                merge [0.0] [0.2]\n\
+                                color [1] [0, 0, 0, 255]\n\
+                                merge [0.1] [0.2]\n\
+                                color [2] [0, 0, 0, 255]\n\
+                                swap [0.3] [2]\n\
+                                color [0.5] [0, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        let errors = validate(&program, &initial_state);
+        let lines = errors.iter().map(|e| e.line_number).collect::<Vec<_>>();
+        assert_eq!(vec![2, 4, 8, 9], lines);
+        assert!(matches!(
+            errors[0].error,
+            SimulateError::LineCutOutOfBounds { .. }
+        ));
+        assert!(matches!(
+            errors[1].error,
+            SimulateError::MergeNotAdjacent { .. }
+        ));
+        assert!(matches!(
+            errors[2].error,
+            SimulateError::SwapShapeMismatch { .. }
+        ));
+        assert!(matches!(errors[3].error, SimulateError::UnknownBlock(_)));
+    }
+
+    #[test]
+    fn test_validate_duplicate_cut() {
+        let initial_state = State::initial_state(8, 8, 0);
+        let program: Program = "cut [0] [X] [4]\n\
+                                cut [0] [X] [2]\n\
+                                cut [0.0] [X] [6]\n\
+                                color [0.1] [0, 0, 0, 255]\n"
+            .parse()
+            .unwrap();
+        let errors = validate(&program, &initial_state);
+        let lines = errors.iter().map(|e| e.line_number).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3], lines);
+        assert!(matches!(
+            errors[0].error,
+            SimulateError::ConsumedBlock { .. }
+        ));
+        assert!(matches!(
+            errors[1].error,
+            SimulateError::LineCutOutOfBounds { .. }
+        ));
+    }
+}
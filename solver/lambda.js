@@ -1,6 +1,9 @@
 const child_process = require("child_process");
 
-exports.handler = async function (event, _context) {
+// 結果の検証とアップロードのために残しておく時間
+const UPLOAD_MARGIN_MS = 60 * 1000;
+
+exports.handler = async function (event, context) {
   const problemId = event.problemId;
   const runId = event.runId;
   const commit = process.env.COMMIT;
@@ -16,6 +19,11 @@ exports.handler = async function (event, _context) {
     `${runId}`,
     ...event.args.trim().split(/\s+/),
   ];
+  // 指定がなければ Lambda のタイムアウトまでに終わるようにする
+  if (!args.includes("--time-limit")) {
+    const ms = context.getRemainingTimeInMillis() - UPLOAD_MARGIN_MS;
+    args.push("--time-limit", `${Math.max(ms, 0) / 1000}`);
+  }
 
   console.log("args", args);
 
@@ -12,6 +12,7 @@ pub async fn save(
     score: i64,
     image_path: &str,
     ai: &str,
+    seed: u64,
     commit: &str,
     elapsed: u64,
     now: u64,
@@ -35,6 +36,8 @@ pub async fn save(
         .item("GSI1PK", AttributeValue::S(gsi1pk))
         .item("GSI1SK", AttributeValue::N(gsi1sk))
         .item("AI", AttributeValue::S(ai.to_string()))
+        // --seed に渡せば手元で再現できる
+        .item("Seed", AttributeValue::N(seed.to_string()))
         .item("Commit", AttributeValue::S(commit.to_string()))
         .item("ExecTime", AttributeValue::N(elapsed.to_string()))
         .item("ExecDate", AttributeValue::N(now.to_string()))
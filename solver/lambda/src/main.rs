@@ -19,7 +19,13 @@ async fn main() -> anyhow::Result<()> {
     let unixtime = now.duration_since(UNIX_EPOCH).expect("back to the future");
     println!("unixtime: {:?}", unixtime.as_secs());
 
-    if let Some(run_id) = output.run_id {
+    // 壊れた解を結果テーブルに入れない
+    output.validate()?;
+
+    if output.kept {
+        // 既存の解はこの run の AI, seed で作ったものではないので、結果テーブルに入れない
+        println!("kept the existing solution, not saved");
+    } else if let Some(run_id) = output.run_id {
         let commit = env::var("COMMIT")?;
         db::save(
             &run_id,
@@ -28,6 +34,7 @@ async fn main() -> anyhow::Result<()> {
             output.score,
             &output.output_image_filename,
             &output.ai,
+            output.seed,
             &commit,
             elapsed.as_secs(),
             unixtime.as_secs(),